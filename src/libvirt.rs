@@ -0,0 +1,284 @@
+use crate::support;
+
+use json::JsonValue;
+
+use std::fs;
+use std::process;
+
+pub fn split_hwtype(hwtype: &str) -> (String, String) {
+    /* HWTYPE:<manufacturer>-<product> as used by saltboot hwtype groups */
+    let hwtype = hwtype.trim_start_matches("HWTYPE:");
+    match hwtype.split_once('-') {
+        Some((manufacturer, product)) => (manufacturer.to_string(), product.to_string()),
+        None => (String::new(), hwtype.to_string()),
+    }
+}
+
+pub fn render_domain(template: &str, terminal: &JsonValue, network: &str) -> String {
+    let (manufacturer, product) = split_hwtype(terminal["hwtype"].as_str().unwrap_or(""));
+    template
+        .replace("{{name}}", terminal["name"].as_str().unwrap())
+        .replace("{{manufacturer}}", &manufacturer)
+        .replace("{{product}}", &product)
+        .replace("{{mac}}", terminal["mac"].as_str().unwrap())
+        .replace("{{network}}", network)
+}
+
+pub fn dhcpd_hosts(terminals: &JsonValue) -> JsonValue {
+    /* Static host entries of dhcpd formula for terminals with fixed address */
+    let mut hosts = JsonValue::new_object();
+    for terminal in terminals.members() {
+        if terminal["ip"].is_null() {
+            continue;
+        }
+        hosts[terminal["name"].as_str().unwrap()] = json::object! {
+            "fixed_address" => terminal["ip"].clone(),
+            "hardware" => format!("ethernet {}", terminal["mac"])
+        };
+    }
+    hosts
+}
+
+pub fn generate_domains() {
+    support::info("STAGE Generation of libvirt domains for terminals.".to_string());
+    let inventory = json::parse(&support::read_text_file("terminals.json")).unwrap();
+    let template_file = inventory["template"]
+        .as_str()
+        .unwrap_or("terminal-template.xml");
+    let template = support::read_text_file(template_file);
+    let network = inventory["network"].as_str().unwrap_or("default");
+    for terminal in inventory["terminals"].members() {
+        let domain = render_domain(&template, terminal, network);
+        support::save_run_file(&format!("{}.xml", terminal["name"]), &domain);
+    }
+    let hosts = dhcpd_hosts(&inventory["terminals"]);
+    support::info(format!(
+        "Static hosts for dhcpd.json:\n{}",
+        json::stringify_pretty(hosts.clone(), 2)
+    ));
+    support::save_run_file("dhcpd-hosts.json", &json::stringify_pretty(hosts, 2));
+    support::info(format!(
+        "Define terminals by: virsh define {}/<terminal>.xml",
+        support::read_env("UYUNI_RUN_DIR")
+    ));
+}
+
+fn xml_value(xml: &str, start: &str, end: &str) -> Option<String> {
+    let from = xml.find(start)? + start.len();
+    let to = xml[from..].find(end)? + from;
+    Some(xml[from..to].trim().to_string())
+}
+
+pub fn parse_domain(xml: &str) -> Option<JsonValue> {
+    /* Read terminal name, smbios manufacturer/product, MAC and network of domain */
+    let name = xml_value(xml, "<name>", "</name>")?;
+    let manufacturer = xml_value(xml, "<entry name='manufacturer'>", "</entry>")?;
+    let product = xml_value(xml, "<entry name='product'>", "</entry>")?;
+    let mac = xml_value(xml, "<mac address='", "'")?;
+    let network = xml_value(xml, "<source network='", "'").unwrap_or_default();
+    Some(json::object! {
+        "name" => name,
+        "hwtype" => format!("HWTYPE:{}-{}", manufacturer, product),
+        "mac" => mac,
+        "network" => network
+    })
+}
+
+pub fn read_domains(directory: &str) -> JsonValue {
+    let mut terminals = JsonValue::new_array();
+    let mut paths: Vec<_> = match fs::read_dir(directory) {
+        Ok(entries) => entries.map(|entry| entry.unwrap().path()).collect(),
+        Err(reason) => {
+            support::error(format!(
+                "Cannot read directory {}, because {:?}",
+                directory, reason
+            ));
+            process::exit(1);
+        }
+    };
+    paths.sort();
+    for path in paths {
+        if path.extension().and_then(|extension| extension.to_str()) != Some("xml") {
+            continue;
+        }
+        let xml = fs::read_to_string(&path).unwrap();
+        match parse_domain(&xml) {
+            Some(terminal) => {
+                support::debug(format!("Terminal {} read from {:?}.", terminal, path));
+                terminals.push(terminal).unwrap();
+            }
+            None => support::warning(format!("File {:?} is not terminal domain.", path)),
+        }
+    }
+    terminals
+}
+
+pub fn import_domains(directory: &str) {
+    support::info(format!(
+        "STAGE Import of terminal domains from {}.",
+        directory
+    ));
+    let mut terminals = read_domains(directory);
+    /* Fixed addresses are kept from terminals.json when MAC matches */
+    let known =
+        support::read_optional_json("terminals.json", JsonValue::new_object())["terminals"].clone();
+    for terminal in terminals.members_mut() {
+        for known_terminal in known.members() {
+            if known_terminal["mac"] == terminal["mac"] && !known_terminal["ip"].is_null() {
+                terminal["ip"] = known_terminal["ip"].clone();
+            }
+        }
+        if terminal["ip"].is_null() {
+            support::warning(format!(
+                "No fixed address known for terminal {}.",
+                terminal["name"]
+            ));
+        }
+    }
+    let mut hwtypes = support::read_optional_json("hwtypes.json", JsonValue::new_array());
+    for terminal in terminals.members() {
+        if !hwtypes
+            .members()
+            .any(|hwtype| hwtype["group"] == terminal["hwtype"])
+        {
+            support::info(format!("New hwtype group {}.", terminal["hwtype"]));
+            hwtypes
+                .push(json::object! {"group" => terminal["hwtype"].clone()})
+                .unwrap();
+        }
+    }
+    let mut dhcpd = json::parse(&support::read_text_file("dhcpd.json")).unwrap();
+    for (name, host) in dhcpd_hosts(&terminals).entries() {
+        dhcpd["dhcpd"]["hosts"][name] = host.clone();
+    }
+    support::save_run_file("hwtypes.json", &json::stringify_pretty(hwtypes.clone(), 2));
+    support::save_run_file("dhcpd.json", &json::stringify_pretty(dhcpd.clone(), 2));
+    support::save_run_file(
+        "terminals.json",
+        &json::stringify_pretty(json::object! {"terminals" => terminals}, 2),
+    );
+    support::info(
+        "Do you wish to update hwtypes.json and dhcpd.json of environment? [y, n]".to_string(),
+    );
+    if support::read_env("UYUNI_YES") == "yes" || support::input().contains('y') {
+        support::write_text_file("hwtypes.json", &json::stringify_pretty(hwtypes, 2));
+        support::write_text_file("dhcpd.json", &json::stringify_pretty(dhcpd, 2));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPLATE: &str = include_str!("../assets/15sp2/terminal-template.xml");
+    const TERMINALS: &str = include_str!("../assets/15sp2/terminals.json");
+
+    #[test]
+    fn split_hwtype_of_group() {
+        assert_eq!(
+            split_hwtype("HWTYPE:Intel-Genuine11"),
+            ("Intel".to_string(), "Genuine11".to_string())
+        );
+        assert_eq!(
+            split_hwtype("Genuine11"),
+            (String::new(), "Genuine11".to_string())
+        );
+    }
+
+    #[test]
+    fn render_domain_from_template() {
+        let inventory = json::parse(TERMINALS).unwrap();
+        for terminal in inventory["terminals"].members() {
+            let domain = render_domain(TEMPLATE, terminal, "suma-bv-41-private");
+            assert!(!domain.contains("{{"));
+            let parsed = parse_domain(&domain).unwrap();
+            assert_eq!(parsed["name"], terminal["name"]);
+            assert_eq!(parsed["hwtype"], terminal["hwtype"]);
+            assert_eq!(parsed["mac"], terminal["mac"]);
+            assert_eq!(parsed["network"], "suma-bv-41-private");
+        }
+    }
+
+    #[test]
+    fn dhcpd_hosts_of_inventory() {
+        let inventory = json::parse(TERMINALS).unwrap();
+        let hosts = dhcpd_hosts(&inventory["terminals"]);
+        assert_eq!(hosts.len(), 3);
+        assert_eq!(
+            hosts["suma-bv-41-terminal-sles15sp2"],
+            json::object! {
+                "fixed_address" => "192.168.41.15",
+                "hardware" => "ethernet 52:54:00:42:f2:25"
+            }
+        );
+    }
+
+    const SLES11SP4: &str = include_str!("../examples/suma-bv-41-terminal-sles11sp4.xml");
+    const SLES12SP4: &str = include_str!("../examples/suma-bv-41-terminal-sles12sp4.xml");
+    const SLES15SP2: &str = include_str!("../examples/suma-bv-41-terminal-sles15sp2.xml");
+
+    fn assert_domain(xml: &str, name: &str, hwtype: &str, mac: &str) {
+        let terminal = parse_domain(xml).unwrap();
+        assert_eq!(terminal["name"], name);
+        assert_eq!(terminal["hwtype"], hwtype);
+        assert_eq!(terminal["mac"], mac);
+        assert_eq!(terminal["network"], "suma-bv-41-private");
+    }
+
+    #[test]
+    fn parse_domain_sles11sp4() {
+        assert_domain(
+            SLES11SP4,
+            "suma-bv-41-terminal-sles11sp4",
+            "HWTYPE:Intel-Genuine11",
+            "52:54:00:92:8f:f8",
+        );
+    }
+
+    #[test]
+    fn parse_domain_with_port_and_bridge() {
+        assert_domain(
+            SLES12SP4,
+            "suma-bv-41-terminal-sles12sp4",
+            "HWTYPE:Intel-Genuine12",
+            "52:54:00:e6:06:e9",
+        );
+    }
+
+    #[test]
+    fn parse_domain_sles15sp2() {
+        assert_domain(
+            SLES15SP2,
+            "suma-bv-41-terminal-sles15sp2",
+            "HWTYPE:Intel-Genuine15",
+            "52:54:00:42:f2:25",
+        );
+    }
+
+    #[test]
+    fn parse_domain_without_sysinfo() {
+        assert!(parse_domain("<domain><name>plain</name></domain>").is_none());
+    }
+
+    #[test]
+    fn dhcpd_hosts_of_parsed_domains() {
+        let mut terminals = JsonValue::new_array();
+        for (xml, ip) in [(SLES11SP4, Some("192.168.41.11")), (SLES12SP4, None)] {
+            let mut terminal = parse_domain(xml).unwrap();
+            if let Some(ip) = ip {
+                terminal["ip"] = ip.into();
+            }
+            terminals.push(terminal).unwrap();
+        }
+        let hosts = dhcpd_hosts(&terminals);
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(
+            hosts["suma-bv-41-terminal-sles11sp4"],
+            json::object! {
+                "fixed_address" => "192.168.41.11",
+                "hardware" => "ethernet 52:54:00:92:8f:f8"
+            }
+        );
+        assert!(hosts["suma-bv-41-terminal-sles12sp4"].is_null());
+    }
+}
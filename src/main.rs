@@ -1,5 +1,6 @@
 extern crate xmlrpc;
 
+mod libvirt;
 mod scenarios;
 mod support;
 
@@ -7,8 +8,8 @@ use std::env;
 use std::process;
 
 fn full_retail_deploy() {
-    scenarios::configure_retail_formulas();
-    scenarios::prepare_buildhost();
+    scenarios::configure_retail_formulas(&support::read_env("UYUNI_BRANCH_SERVER"));
+    scenarios::prepare_buildhost(&support::read_env("UYUNI_BUILD_HOST"));
     scenarios::prepare_kiwi_profile();
     scenarios::build_kiwi_image();
     scenarios::configure_saltboot();
@@ -29,9 +30,10 @@ fn basic_tests() {
 fn help() {
     println!(
         "Usage:
-    semi-xmlrpc-tester [environment] [scenario] [-y] [--silent | --debug]
+    semi-xmlrpc-tester [environment] [scenario] [-y] [--recreate] [--force] [--silent | --debug]
 
     ! Working directory must contain assets directory with environments !
+    ! Failure reports are saved to runs/[environment]-[timestamp] directory !
 
     Example:
     semi-xmlrpc-tester 15sp2 basic_test -y --debug
@@ -39,6 +41,10 @@ fn help() {
     Options:
     ========
     -y          - answer all questions of programm 'yes'
+    --recreate  - delete and create existing system groups (members and formulas are lost)
+                  and kiwi profile
+    --force     - delete also groups, profiles, images, stores and activation keys
+                  not listed in ownership ledger (state/[environment]/created.json)
     --silent    - run programm without any printed output
     --debug     - set log level to DEBUG (default is INFO)
 
@@ -49,15 +55,44 @@ fn help() {
     Scenarios:
     ==========
     basic_tests - log to server and print users on it
+    bootstrap   - bootstrap branch server and build host (described in bootstrap.json)
+                  and configure them by their role (branch is activated as proxy by
+                  its proxy_setup command before retail formulas), password of
+                  hosts is read from UYUNI_BOOTSTRAP_PASSWORD or asked for
     formulas    - configure all retail formulas at branch server
+    check_branch - check branch server is proxy with packages, channels and nic
+                  (described in branch-server.json and branch-network.json)
     buildhost   - set entitlement for buildhost and apply highstate
-    profiles    - prepare kiwi image profiles
-    image       - build kiwi image
-    saltboot    - configure saltboot formula
+    check_buildhost - check buildhost readiness (minion, checkin, channels in buildhost.json)
+    profiles    - prepare kiwi image profiles (options and custom info in kiwi-profile.json)
+    image       - build kiwi image according to image_policy and verify it
+    images      - build kiwi images in parallel (described in builds.json)
+    verify_image - verify inspection of built kiwi image and its presence in branch pillar
+    container   - prepare dockerfile profile, build and inspect container image (container.json)
+    stores      - create image stores (described in image-stores.json)
+    stores_list - list image stores at server
+    stores_delete - delete image stores (described in image-stores.json)
+    saltboot    - configure saltboot formula of hwtype groups (described in hwtypes.json)
     prepare     - create necessary groups and apply highstate at branch server
     full        - run full deployment (all above) in proper order (as above)
+    terminals   - accept salt keys of PXE booted terminals (described in terminals.json),
+                  wait for their registration and saltboot and verify their groups
+    verify_terminals - compare terminals.json (hostname, MAC, groups, booted image version)
+                  with server and print pass/fail table
     image_sync  - configure image sync formula only
-    clone_key   - clone existing activation key (described in config.json)
+    clone_key   - clone existing activation key (described in config.json) and verify it
+                  against terminal-key.json
+    keys        - create or reconcile activation keys (described in activation-keys.json)
+    domains     - render libvirt domains and dhcpd hosts of terminals (described in terminals.json)
+    inventory [directory] - derive hwtype groups and dhcpd hosts from libvirt domains
+                  in directory (default examples) and update hwtypes.json and dhcpd.json
+    group_formulas - assign formulas to system groups (described in group-formulas.json)
+    teardown    - delete objects created by the tool in reverse order (listed
+                  in ownership ledger state/[environment]/created.json)
+
+    Exit codes:
+    ===========
+    0 - success, 1 - failure of scenario, 2 - scheduled action did not finish in time
     "
     );
 }
@@ -80,6 +115,16 @@ fn main() {
     } else {
         env::set_var("UYUNI_YES", "no");
     }
+    if args.contains(&"--recreate".to_string()) {
+        env::set_var("UYUNI_RECREATE", "yes"); // Delete and create existing groups and profiles
+    } else {
+        env::set_var("UYUNI_RECREATE", "no");
+    }
+    if args.contains(&"--force".to_string()) {
+        env::set_var("UYUNI_FORCE", "yes"); // Delete also objects not created by the tool
+    } else {
+        env::set_var("UYUNI_FORCE", "no");
+    }
     if args.contains(&"--debug".to_string()) {
         env::set_var("UYUNI_LOG_LEVEL", "DEBUG");
     } else if args.contains(&"--silent".to_string()) {
@@ -91,22 +136,62 @@ fn main() {
         "Log level set to {}.",
         support::read_env("UYUNI_LOG_LEVEL")
     ));
+    env::set_var(
+        "UYUNI_RUN_DIR",
+        format!(
+            "runs/{}-{}",
+            &args[1],
+            chrono::Local::now().format("%Y%m%d-%H%M%S")
+        ),
+    );
     support::import_json_data("config.json");
 
+    /* Scenarios working with local files only do not log in to server */
+    if args[2] == "domains" {
+        libvirt::generate_domains();
+        process::exit(0);
+    }
+    if args[2] == "inventory" {
+        match args.get(3) {
+            Some(directory) if !directory.starts_with('-') => libvirt::import_domains(directory),
+            _ => libvirt::import_domains("examples"),
+        }
+        process::exit(0);
+    }
+
     let key = support::call_server("auth.login", None);
     env::set_var("UYUNI_KEY", key.as_str().unwrap());
 
     match args[2].as_str() {
         "basic_tests" => basic_tests(),
-        "formulas" => scenarios::configure_retail_formulas(),
+        "bootstrap" => scenarios::bootstrap_servers(),
+        "formulas" => {
+            scenarios::configure_retail_formulas(&support::read_env("UYUNI_BRANCH_SERVER"))
+        }
+        "check_branch" => scenarios::check_branch_server(&support::read_env("UYUNI_BRANCH_SERVER")),
         "full" => full_retail_deploy(),
-        "buildhost" => scenarios::prepare_buildhost(),
+        "buildhost" => scenarios::prepare_buildhost(&support::read_env("UYUNI_BUILD_HOST")),
+        "check_buildhost" => scenarios::check_buildhost(
+            &support::read_env("UYUNI_BUILD_HOST"),
+            &support::read_env("UYUNI_ACTIVATION_KEY"),
+        ),
         "profiles" => scenarios::prepare_kiwi_profile(),
         "image" => scenarios::build_kiwi_image(),
+        "images" => scenarios::build_kiwi_images(),
+        "verify_image" => scenarios::verify_kiwi_image(),
+        "container" => scenarios::build_container_image(),
+        "stores" => scenarios::prepare_image_stores(),
+        "stores_list" => scenarios::list_image_stores(),
+        "stores_delete" => scenarios::delete_image_stores(),
         "saltboot" => scenarios::configure_saltboot(),
         "prepare" => scenarios::prepare_for_deployment(),
         "image_sync" => scenarios::configure_image_sync_formula(),
         "clone_key" => scenarios::clone_terminal_activation_key(),
+        "keys" => scenarios::prepare_activation_keys(),
+        "terminals" => scenarios::onboard_terminals(),
+        "verify_terminals" => scenarios::verify_terminals(),
+        "group_formulas" => scenarios::configure_group_formulas(),
+        "teardown" => scenarios::teardown(),
         _ => {
             support::error("Incorrect argument string passed.".to_string());
             process::exit(1);
@@ -1,113 +1,542 @@
+use crate::libvirt;
 use crate::support;
 
+use json::JsonValue;
+
+use std::env;
 use std::process;
 use std::{thread, time};
 
-pub fn prepare_buildhost() {
-    support::info("Preparation of buildhost.".to_string());
+pub fn prepare_buildhost(build_host: &str) {
+    support::info(format!("Preparation of buildhost {}.", build_host));
     /* Prepare buildhost server */
-    if !support::has_buildhost_entitlement() {
-        support::add_buildhost_entitlement();
+    if !support::has_buildhost_entitlement(build_host) {
+        support::add_buildhost_entitlement(build_host);
     }
-    let event_id = support::schedule_highstate(support::read_env("UYUNI_BUILD_HOST"));
-    support::wait_for_highstate(&support::read_env("UYUNI_BUILD_HOST"), event_id, 80, 15);
+    let system_id = support::get_system_id(build_host.to_string());
+    let event_id = support::schedule_highstate(build_host.to_string());
+    let outcome = support::wait_for_action(
+        event_id,
+        &[system_id],
+        support::read_env_or("UYUNI_HIGHSTATE_INTERVAL", 15),
+        support::read_env_or("UYUNI_HIGHSTATE_TIMEOUT", 1200),
+    );
+    support::expect_action("Highstate", event_id, outcome);
+}
+
+pub fn check_buildhost(build_host: &str, activation_key: &str) {
+    support::info(format!("Checking readiness of buildhost {}.", build_host));
+    let settings = support::read_optional_json("buildhost.json", JsonValue::new_object());
+    support::fail_with_report(&support::check_buildhost(
+        build_host,
+        activation_key,
+        &settings,
+    ));
+    support::info(format!("Buildhost {} is ready.", build_host));
 }
 
 pub fn prepare_kiwi_profile() {
     support::info("STAGE Preparation of kiwi profile.".to_string());
-    /* Prepare Kiwi image profile and rewrite old one if necessary */
-    if support::exists_kiwi_profile() {
-        support::delete_kiwi_profile();
+    /* Prepare Kiwi image profile and update existing one in place */
+    let profile = support::read_optional_json("kiwi-profile.json", JsonValue::new_object());
+    ensure_image_store(&support::read_env("UYUNI_IMAGE_STORE"));
+    let exists = support::exists_kiwi_profile();
+    /* Profile not created by the tool is updated in place unless --force is passed */
+    let deleted =
+        exists && support::read_env("UYUNI_RECREATE") == "yes" && support::delete_kiwi_profile();
+    if !exists || deleted {
+        support::create_kiwi_profile();
+    }
+    support::set_kiwi_profile_details(profile["kiwi_options"].as_str().unwrap_or(""));
+    if !profile["custom_info"].is_empty() {
+        support::set_kiwi_profile_custom_values(&profile["custom_info"]);
+    }
+}
+
+struct KiwiBuild {
+    profile: String,
+    profile_path: String,
+    build_host: String,
+    activation_key: String,
+    initrd: String,
+}
+
+impl KiwiBuild {
+    fn from_env() -> KiwiBuild {
+        KiwiBuild {
+            profile: support::read_env("UYUNI_KIWI_PROFILE"),
+            profile_path: support::read_env("UYUNI_PROFILE_PATH"),
+            build_host: support::read_env("UYUNI_BUILD_HOST"),
+            activation_key: support::read_env("UYUNI_ACTIVATION_KEY"),
+            initrd: support::read_env("UYUNI_KIWI_INITRD"),
+        }
+    }
+
+    fn from_builds_entry(build: &JsonValue) -> KiwiBuild {
+        /* Source path and activation key are taken from existing kiwi profile */
+        let profile = build["kiwi_profile"].to_string();
+        let details = support::get_image_profile_details(&profile);
+        KiwiBuild {
+            profile_path: details["path"].as_str().unwrap_or("").to_string(),
+            activation_key: details["activationKey"].as_str().unwrap_or("").to_string(),
+            build_host: build["build_host"].to_string(),
+            initrd: build["kiwi_initrd"].to_string(),
+            profile,
+        }
     }
-    support::create_kiwi_profile();
+
+    fn list_images(&self) -> Vec<support::ImageRecord> {
+        let (name, version) = support::split_image_name_version(&self.initrd);
+        support::list_images(&self.profile, &name, &version)
+    }
+}
+
+enum KiwiPlan {
+    Keep(support::ImageRecord),
+    Build(Option<i32>), // Image replaced by new build
+}
+
+enum KiwiBuildState {
+    Kept(support::ImageRecord),
+    Running(i32, i32, thread::JoinHandle<(support::ActionOutcome, u64)>), // Action, buildhost
 }
 
 pub fn build_kiwi_image() {
     support::info("STAGE Building of kiwi image.".to_string());
-    /* Building kiwi images */
-    let image = support::exists_kiwi_image();
-    if image.contains_key(&true) {
-        // Image already exists tree
-        let image_id = image[&true];
-        let status = support::status_kiwi_image(image_id);
-        match status.as_str() {
-            "queued" | "picked up" | "completed" => {
-                support::info(format!("Kiwi image status: *{}*.", status.as_str()));
-                support::info("Do you wish to delete (or cancel process of) existing image and build again? [y, n]".to_string());
-                if support::read_env("UYUNI_YES") == "yes" || support::input().contains("y") {
-                    support::delete_kiwi_image(image_id);
-                    support::schedule_kiwi_image();
+    let (status, _, failures) = run_kiwi_builds(&[KiwiBuild::from_env()]).remove(0);
+    if status == "timed out" {
+        for failure in failures {
+            support::error(failure);
+        }
+        process::exit(2);
+    }
+    support::fail_with_report(&failures);
+    support::info("Kiwi image is ready for deployment.".to_string());
+}
+
+fn run_kiwi_builds(builds: &[KiwiBuild]) -> Vec<(String, u64, Vec<String>)> {
+    /* Building kiwi images according to image_policy: revision (default, previous image
+    stays bootable during build), rebuild, skip, source_changed */
+    let policy = env::var("UYUNI_IMAGE_POLICY").unwrap_or_else(|_| "revision".to_string());
+    if !["rebuild", "skip", "revision", "source_changed"].contains(&policy.as_str()) {
+        support::error(format!("Unknown image policy {:?}.", policy));
+        process::exit(1);
+    }
+    /* All builds are planned and their buildhosts checked before any of them is scheduled */
+    let mut plans = Vec::new();
+    for build in builds {
+        let source_revision = if policy == "source_changed" {
+            support::kiwi_source_revision(&build.profile_path)
+        } else {
+            String::new()
+        };
+        let plan = plan_kiwi_build(build, &policy, &source_revision);
+        plans.push((plan, source_revision));
+    }
+    let mut states = Vec::new();
+    for (build, (plan, source_revision)) in builds.iter().zip(plans) {
+        let state = match plan {
+            KiwiPlan::Keep(image) => KiwiBuildState::Kept(image),
+            KiwiPlan::Build(replaced_image) => {
+                let action_id = schedule_kiwi_build(build, replaced_image);
+                let build_host_id = support::get_system_id(build.build_host.clone());
+                let waiting = thread::spawn(move || {
+                    let started = time::Instant::now();
+                    let outcome = support::wait_for_action(
+                        action_id,
+                        &[build_host_id],
+                        support::read_env_or("UYUNI_BUILD_INTERVAL", 60),
+                        support::read_env_or("UYUNI_BUILD_TIMEOUT", 2400),
+                    );
+                    (outcome, started.elapsed().as_secs())
+                });
+                KiwiBuildState::Running(action_id, build_host_id, waiting)
+            }
+        };
+        states.push((state, source_revision));
+    }
+    let mut results = Vec::new();
+    for (build, (state, source_revision)) in builds.iter().zip(states) {
+        let (built, duration) = match state {
+            KiwiBuildState::Kept(image) => {
+                support::info(format!(
+                    "Building of kiwi image {} skipped, revision {} is kept.",
+                    build.profile, image.revision
+                ));
+                (Ok(image), 0)
+            }
+            KiwiBuildState::Running(action_id, build_host_id, waiting) => {
+                let (outcome, duration) = waiting.join().unwrap();
+                (
+                    built_kiwi_image(build, action_id, build_host_id, outcome),
+                    duration,
+                )
+            }
+        };
+        results.push(match built {
+            Ok(image) => {
+                let failures = finish_kiwi_image(build, &image, &source_revision);
+                let status = if failures.is_empty() {
+                    "completed"
                 } else {
-                    process::exit(0);
-                }
+                    "unverified"
+                };
+                (status.to_string(), duration, failures)
             }
-            "failed" => {
-                support::delete_kiwi_image(image_id);
-                support::schedule_kiwi_image();
+            Err((status, failure)) => (status, duration, vec![failure]),
+        });
+    }
+    results
+}
+
+fn plan_kiwi_build(build: &KiwiBuild, policy: &str, source_revision: &str) -> KiwiPlan {
+    let plan = match build.list_images().pop() {
+        Some(image) => plan_by_policy(build, image, policy, source_revision),
+        None => KiwiPlan::Build(None),
+    };
+    if let KiwiPlan::Build(_) = plan {
+        /* Buildhost is checked only when image is going to be built */
+        check_buildhost(&build.build_host, &build.activation_key);
+    }
+    plan
+}
+
+fn plan_by_policy(
+    build: &KiwiBuild,
+    image: support::ImageRecord,
+    policy: &str,
+    source_revision: &str,
+) -> KiwiPlan {
+    // Image already exists tree
+    let status = support::status_kiwi_image(image.id);
+    support::info(format!(
+        "Kiwi image {} status: *{}*.",
+        build.profile,
+        status.as_str()
+    ));
+    match (policy, status.as_str()) {
+        ("rebuild", "queued" | "picked up" | "completed") => {
+            support::info(format!(
+                "Do you wish to delete (or cancel process of) existing image {} and build again? [y, n]",
+                build.profile
+            ));
+            if support::read_env("UYUNI_YES") == "yes" || support::input().contains('y') {
+                return KiwiPlan::Build(Some(image.id));
             }
-            _ => {
-                support::error(format!(
-                    "Better not to imagine what happened to poor kiwi image."
+            process::exit(0);
+        }
+        (_, "queued" | "picked up") => {
+            support::info(format!(
+                "Kiwi image {} is being built already, try it later.",
+                build.profile
+            ));
+            process::exit(0);
+        }
+        ("skip", "completed") => KiwiPlan::Keep(image),
+        ("source_changed", "completed")
+            if !support::kiwi_source_changed(&build.profile, source_revision) =>
+        {
+            KiwiPlan::Keep(image)
+        }
+        (_, "completed") => KiwiPlan::Build(None),
+        (_, "failed") => KiwiPlan::Build(Some(image.id)),
+        _ => {
+            support::error("Better not to imagine what happened to poor kiwi image.".to_string());
+            process::exit(1);
+        }
+    }
+}
+
+fn schedule_kiwi_build(build: &KiwiBuild, replaced_image: Option<i32>) -> i32 {
+    /* Replaced image is deleted only after buildhost was checked */
+    if let Some(image_id) = replaced_image {
+        support::delete_kiwi_image(image_id);
+    }
+    support::schedule_image_build(&build.profile, "", &build.build_host)
+}
+
+fn built_kiwi_image(
+    build: &KiwiBuild,
+    action_id: i32,
+    build_host_id: i32,
+    outcome: support::ActionOutcome,
+) -> Result<support::ImageRecord, (String, String)> {
+    let (status, failure) = match outcome {
+        support::ActionOutcome::Completed => match build.list_images().pop() {
+            Some(image) if image.status == "completed" => {
+                support::info(format!(
+                    "Kiwi image {}-{} revision {} completed.",
+                    image.name, image.version, image.revision
                 ));
-                process::exit(1);
+                return Ok(image);
             }
+            _ => (
+                "missing",
+                format!(
+                    "Kiwi image building of {} finished, but image is not completed.",
+                    build.profile
+                ),
+            ),
+        },
+        support::ActionOutcome::Failed(system_ids) => (
+            "failed",
+            format!(
+                "Kiwi image building of {} failed at systems {:?}.",
+                build.profile, system_ids
+            ),
+        ),
+        support::ActionOutcome::TimedOut(system_ids) => {
+            return Err((
+                "timed out".to_string(),
+                format!(
+                    "Kiwi image building of {} did not finish in time at systems {:?}.",
+                    build.profile, system_ids
+                ),
+            ));
+        }
+    };
+    support::report_kiwi_build_failure(&build.profile, build_host_id, action_id);
+    Err((status.to_string(), failure))
+}
+
+fn finish_kiwi_image(
+    build: &KiwiBuild,
+    image: &support::ImageRecord,
+    source_revision: &str,
+) -> Vec<String> {
+    /* Source revision is saved and older revisions pruned only for verified image */
+    support::info(format!("Verification of kiwi image {}.", build.profile));
+    let failures = support::verify_kiwi_image(image.id, &build.initrd);
+    if failures.is_empty() {
+        if !source_revision.is_empty() {
+            support::save_kiwi_source(&build.profile, source_revision);
         }
+        support::prune_kiwi_images(&build.list_images());
+    }
+    failures
+}
+
+pub fn verify_kiwi_image() {
+    support::info("STAGE Verification of kiwi image.".to_string());
+    let image_id = match support::list_kiwi_images().last() {
+        Some(image) => image.id,
+        None => {
+            support::error("No kiwi image to verify.".to_string());
+            process::exit(1);
+        }
+    };
+    support::fail_with_report(&support::verify_kiwi_image(
+        image_id,
+        &support::read_env("UYUNI_KIWI_INITRD"),
+    ));
+    support::info("Kiwi image is ready for deployment.".to_string());
+}
+
+pub fn build_kiwi_images() {
+    support::info("STAGE Parallel building of kiwi images.".to_string());
+    /* Builds are described in builds.json as pairs of kiwi profile and build host */
+    let builds: Vec<KiwiBuild> = json::parse(&support::read_text_file("builds.json"))
+        .unwrap()
+        .members()
+        .map(KiwiBuild::from_builds_entry)
+        .collect();
+    let mut report = String::new();
+    let mut failed = false;
+    for (build, (status, duration, failures)) in builds.iter().zip(run_kiwi_builds(&builds)) {
+        for failure in failures {
+            support::error(failure.clone());
+            report.push_str(&format!("{}\n", failure));
+        }
+        let line = format!(
+            "{:<20} {:<50} {:<10} {} seconds",
+            build.profile, build.build_host, status, duration
+        );
+        if status == "completed" {
+            support::info(line.clone());
+        } else {
+            support::error(line.clone());
+            failed = true;
+        }
+        report.push_str(&format!("{}\n", line));
+    }
+    support::save_report("report.log", &report);
+    if failed {
+        process::exit(1);
+    }
+}
+
+pub fn build_container_image() {
+    support::info("STAGE Building of container image.".to_string());
+    let container = json::parse(&support::read_text_file("container.json")).unwrap();
+    let label = container["label"].to_string();
+    let version = container["version"]
+        .as_str()
+        .unwrap_or("latest")
+        .to_string();
+    let build_host = match container["build_host"].as_str() {
+        Some(build_host) => build_host.to_string(),
+        None => support::read_env("UYUNI_BUILD_HOST"),
+    };
+    let store = container["image_store"].to_string();
+    let path = container["path"].to_string();
+    let activation_key = container["activation_key"].to_string();
+    ensure_image_store(&store);
+    if support::exists_image_profile(&label) {
+        support::set_image_profile_details(&label, &store, &path, &activation_key, None);
     } else {
-        support::schedule_kiwi_image();
-    }
-    // Image is being built tree
-    let image = support::exists_kiwi_image();
-    if image.contains_key(&true) {
-        // Check status of image
-        let step_time = 60;
-        let step = time::Duration::from_secs(step_time);
-        let image_id = image[&true];
-        for i in 1..40 {
-            thread::sleep(step);
-            let status = support::status_kiwi_image(image_id);
-            match status.as_str() {
-                "queued" | "picked up" => support::info(format!(
-                    "Kiwi image building is *{}* {} after seconds.",
-                    status.as_str(),
-                    i * step_time
-                )),
-                "completed" => {
-                    support::info(format!(
-                        "Kiwi image building is *{}* {} after seconds.",
-                        status.as_str(),
-                        i * step_time
-                    ));
-                    break;
-                }
-                "failed" => {
-                    support::error(format!(
-                        "Kiwi image building failed after {} seconds.",
-                        i * step_time
-                    ));
-                    process::exit(1);
-                }
-                _ => {
-                    support::error(format!("Better not to imagine that."));
-                    process::exit(1);
-                }
-            }
+        support::create_image_profile(&label, "dockerfile", &store, &path, &activation_key);
+    }
+    let action_id = support::schedule_image_build(&label, &version, &build_host);
+    let build_host_id = support::get_system_id(build_host);
+    let outcome = support::wait_for_action(
+        action_id,
+        &[build_host_id],
+        support::read_env_or("UYUNI_BUILD_INTERVAL", 60),
+        support::read_env_or("UYUNI_BUILD_TIMEOUT", 2400),
+    );
+    support::expect_action("Container image building", action_id, outcome);
+    let image = match support::list_images(&label, &label, &version).pop() {
+        Some(image) if image.status == "completed" => image,
+        _ => {
+            support::error(
+                "Container image building finished, but image is not completed.".to_string(),
+            );
+            process::exit(1);
+        }
+    };
+    let (_, failures) = support::verify_image_inspection(image.id);
+    support::fail_with_report(&failures);
+    support::info(format!(
+        "Container image {}:{} revision {} is ready.",
+        image.name, image.version, image.revision
+    ));
+}
+
+fn read_image_stores() -> JsonValue {
+    /* Image stores are optional part of environment */
+    support::read_optional_json("image-stores.json", JsonValue::new_array())
+}
+
+fn ensure_image_store(label: &str) {
+    /* Store referenced by profile is created when described in image-stores.json */
+    if support::exists_image_store(label) {
+        return;
+    }
+    for store in read_image_stores().members() {
+        if store["label"] == label {
+            support::create_image_store(store);
+            return;
+        }
+    }
+    support::error(format!(
+        "Image store {} does not exist and is not described in image-stores.json.",
+        label
+    ));
+    process::exit(1);
+}
+
+pub fn list_image_stores() {
+    support::info("STAGE Listing of image stores.".to_string());
+    for store in support::list_image_stores() {
+        support::info(format!(
+            "Image store {} ({}): {}",
+            store["label"].as_str().unwrap_or(""),
+            store["storetype"].as_str().unwrap_or(""),
+            store["uri"].as_str().unwrap_or("")
+        ));
+    }
+}
+
+pub fn prepare_image_stores() {
+    support::info("STAGE Preparation of image stores.".to_string());
+    for store in read_image_stores().members() {
+        ensure_image_store(store["label"].as_str().unwrap());
+    }
+}
+
+pub fn delete_image_stores() {
+    support::info("STAGE Deletion of image stores.".to_string());
+    for store in read_image_stores().members() {
+        let label = store["label"].as_str().unwrap();
+        if support::exists_image_store(label) {
+            support::delete_image_store(label);
         }
     }
 }
 
 pub fn configure_saltboot() {
     support::info("STAGE Configuration of salboot formula.".to_string());
-    let hwgroup_name = support::read_env("UYUNI_HWTYPE_GROUP");
-    if support::exists_system_group(&hwgroup_name) {
-        support::delete_system_group(&hwgroup_name);
+    let group_formulas = read_group_formulas();
+    for hwtype in read_hwtypes().members() {
+        let hwgroup_name = hwtype["group"].to_string();
+        /* Saltboot data of hwtype group are rendered from template, other formulas as they are */
+        let mut formulas = group_formulas[hwgroup_name.as_str()].clone();
+        let description = match (
+            hwtype["description"].as_str(),
+            formulas.remove("description").as_str(),
+        ) {
+            (Some(description), _) | (None, Some(description)) => description.to_string(),
+            (None, None) => hwgroup_name.clone(),
+        };
+        let hwgroup_id = support::ensure_system_group(&hwgroup_name, &description);
+        let saltboot_file = match formulas.remove("saltboot").as_str() {
+            Some(file) => file.to_string(),
+            None => "saltboot.json".to_string(),
+        };
+        if !formulas.is_empty() {
+            support::configure_group_formulas(hwgroup_id, &formulas);
+        }
+        support::set_group_formulas(hwgroup_id, vec!["saltboot"]);
+        let template = json::parse(&support::read_text_file(&saltboot_file)).unwrap();
+        support::set_group_formula_data(
+            hwgroup_id,
+            "saltboot",
+            &support::saltboot_partitioning(&template, hwtype),
+        );
+    }
+}
+
+fn read_hwtypes() -> JsonValue {
+    /* Without hwtypes.json only hwtype group from config.json is configured */
+    support::read_optional_json(
+        "hwtypes.json",
+        json::array![json::object! {"group" => support::read_env("UYUNI_HWTYPE_GROUP")}],
+    )
+}
+
+fn read_group_formulas() -> JsonValue {
+    /* Formulas of groups as {"group": {"formula": "data_file.json", "description": "..."}} */
+    support::read_optional_json("group-formulas.json", JsonValue::new_object())
+}
+
+pub fn configure_group_formulas() {
+    support::info("STAGE Configuration of system group formulas.".to_string());
+    let group_formulas = read_group_formulas();
+    for (group_name, formulas) in group_formulas.entries() {
+        let mut formulas = formulas.clone();
+        let description = match formulas.remove("description").as_str() {
+            Some(description) => description.to_string(),
+            None => group_name.to_string(),
+        };
+        let group_id = support::ensure_system_group(group_name, &description);
+        support::configure_group_formulas(group_id, &formulas);
     }
-    let hwgroup_id = support::create_system_group(&hwgroup_name);
-    support::set_saltboot_formula(hwgroup_id);
 }
 
-pub fn configure_retail_formulas() {
+pub fn check_branch_server(branch_server: &str) {
+    support::info(format!(
+        "Pre-flight check of branch server {}.",
+        branch_server
+    ));
+    let settings = support::read_optional_json("branch-server.json", JsonValue::new_object());
+    support::fail_with_report(&support::check_branch_server(branch_server, &settings));
+    support::info(format!("Branch server {} is ready.", branch_server));
+}
+
+pub fn configure_retail_formulas(branch_server: &str) {
     support::info("STAGE Configuration of retail formulas.".to_string());
-    let rbs_id = support::get_system_id(support::read_env("UYUNI_BRANCH_SERVER"));
+    check_branch_server(branch_server);
+    let rbs_id = support::get_system_id(branch_server.to_string());
     let formulas = vec![
         "branch-network",
         "dhcpd",
@@ -141,24 +570,528 @@ pub fn configure_image_sync_formula() {
 
 pub fn prepare_for_deployment() {
     support::info("INFO: Preparing of groups and  higstate to branch server.".to_string());
-    let system_groups = vec!["SERVERS", "TERMINALS", "id"];
+    let branch_id = support::get_branch_id();
+    let system_groups = vec!["SERVERS", "TERMINALS", branch_id.as_str()];
     for group in system_groups {
-        if support::exists_system_group(&group) {
-            support::delete_system_group(&group);
-        }
-        let group_id = support::create_system_group(&group);
+        let group_id = support::ensure_system_group(group, group);
         support::info(format!(
-            "Group {:?} with id {:?} created.",
+            "Group {:?} with id {:?} prepared.",
             &group, group_id
         ));
     }
+    let rbs_id = support::get_system_id(support::read_env("UYUNI_BRANCH_SERVER"));
+    for group in ["SERVERS", branch_id.as_str()] {
+        support::add_systems_to_group(group, vec![rbs_id]);
+    }
     let event_id = support::schedule_highstate(support::read_env("UYUNI_BRANCH_SERVER"));
-    support::wait_for_highstate(&support::read_env("UYUNI_BRANCH_SERVER"), event_id, 20, 30);
+    let outcome = support::wait_for_action(
+        event_id,
+        &[rbs_id],
+        support::read_env_or("UYUNI_HIGHSTATE_INTERVAL", 30),
+        support::read_env_or("UYUNI_HIGHSTATE_TIMEOUT", 600),
+    );
+    support::expect_action("Highstate", event_id, outcome);
 }
 
 pub fn clone_terminal_activation_key() {
-    support::info(format!(
-        "Activation key {:?} created.",
-        support::clone_activation_key(&support::read_env("UYUNI_ACTIVATION_KEY"))
-    ));    
+    let full_key = support::clone_activation_key(&support::read_env("UYUNI_ACTIVATION_KEY"));
+    support::info(format!("Activation key {:?} created.", full_key));
+    verify_terminal_activation_key(&full_key);
+}
+
+fn terminal_key_spec() -> JsonValue {
+    /* Expectation of terminal key from terminal-key.json, by default channels and entitlements
+    of cloned key, values beyond listed ones are drift as well */
+    let mut spec = support::read_optional_json("terminal-key.json", JsonValue::Null);
+    if spec.is_null() {
+        let cloned = support::get_activation_key_details(&support::read_env("UYUNI_CLONNED_KEY"));
+        spec = JsonValue::new_object();
+        spec["base_channel"] = cloned["base_channel_label"].as_str().unwrap_or("").into();
+        spec["child_channels"] = JsonValue::new_array();
+        for child in cloned["child_channel_labels"].as_array().unwrap_or(&[]) {
+            spec["child_channels"]
+                .push(child.as_str().unwrap_or(""))
+                .unwrap();
+        }
+        spec["entitlements"] = JsonValue::new_array();
+        for entitlement in cloned["entitlements"].as_array().unwrap_or(&[]) {
+            spec["entitlements"]
+                .push(entitlement.as_str().unwrap_or(""))
+                .unwrap();
+        }
+        spec["contact_method"] = "default".into();
+    }
+    if !spec["server_groups"].is_array() {
+        spec["server_groups"] = JsonValue::new_array();
+    }
+    for group in ["TERMINALS".to_string(), support::get_branch_id()] {
+        if !spec["server_groups"].contains(group.as_str()) {
+            spec["server_groups"].push(group).unwrap();
+        }
+    }
+    spec
+}
+
+pub fn verify_terminal_activation_key(full_key: &str) {
+    support::info(format!("Verification of activation key {}.", full_key));
+    let spec = terminal_key_spec();
+    let drift = support::reconcile_activation_key(full_key, &spec, false);
+    if drift.is_empty() {
+        support::info(format!("Activation key {} is retail ready.", full_key));
+        return;
+    }
+    support::save_report(
+        "report.log",
+        &format!("Activation key {} drift:\n{}\n", full_key, drift.join("\n")),
+    );
+    support::info("Do you wish to fix the activation key? [y, n]".to_string());
+    if support::read_env("UYUNI_YES") != "yes" && !support::input().contains('y') {
+        process::exit(1);
+    }
+    support::reconcile_activation_key(full_key, &spec, true);
+    /* Drift like missing server group cannot be fixed by reconciliation */
+    let drift = support::reconcile_activation_key(full_key, &spec, false);
+    if !drift.is_empty() {
+        for item in &drift {
+            support::error(format!("Activation key {}: {}", full_key, item));
+        }
+        support::save_report(
+            "report.log",
+            &format!(
+                "Activation key {} drift after fix:\n{}\n",
+                full_key,
+                drift.join("\n")
+            ),
+        );
+        process::exit(1);
+    }
+    support::info(format!("Activation key {} is retail ready.", full_key));
+}
+
+pub fn prepare_activation_keys() {
+    support::info("STAGE Preparation of activation keys.".to_string());
+    let specs = json::parse(&support::read_text_file("activation-keys.json")).unwrap();
+    for spec in specs.members() {
+        let full_key = match support::find_activation_key(spec["key"].as_str().unwrap()) {
+            Some(full_key) => full_key,
+            None => support::create_activation_key(spec),
+        };
+        support::reconcile_activation_key(&full_key, spec, true);
+        /* Drift like missing server group cannot be fixed by reconciliation */
+        let drift = support::reconcile_activation_key(&full_key, spec, false);
+        if !drift.is_empty() {
+            for item in &drift {
+                support::error(format!("Activation key {}: {}", full_key, item));
+            }
+            support::save_report(
+                "report.log",
+                &format!(
+                    "Activation key {} drift after fix:\n{}\n",
+                    full_key,
+                    drift.join("\n")
+                ),
+            );
+            process::exit(1);
+        }
+    }
+}
+
+fn expected_minion_id(terminal: &JsonValue, naming: &str, domain: &str) -> String {
+    /* Minion ID of terminal as given by minion_id_naming of pxe formula */
+    let name = terminal["name"].as_str().unwrap_or("");
+    match naming {
+        "FQDN" => format!("{}.{}", name, domain),
+        "MAC" => terminal["mac"].to_string().replace(':', "-"),
+        "HWType" => {
+            let (manufacturer, product) =
+                libvirt::split_hwtype(terminal["hwtype"].as_str().unwrap_or(""));
+            format!("{}-{}", manufacturer, product)
+        }
+        _ => name.to_string(),
+    }
+}
+
+fn minion_matches(minion_id: &str, expected: &str, naming: &str) -> bool {
+    /* With HWType naming only prefix is known, serial number follows */
+    if naming == "HWType" {
+        return minion_id.starts_with(expected);
+    }
+    minion_id == expected
+}
+
+fn read_terminals() -> JsonValue {
+    json::parse(&support::read_text_file("terminals.json")).unwrap()
+}
+
+fn read_formula_file(formula_file: &str) -> JsonValue {
+    json::parse(&support::read_text_file(formula_file)).unwrap()
+}
+
+pub fn onboard_terminals() {
+    support::info("STAGE Onboarding of PXE booted terminals.".to_string());
+    let terminals = read_terminals()["terminals"].clone();
+    let naming = read_formula_file("branch-network.json")["pxe"]["minion_id_naming"]
+        .as_str()
+        .unwrap_or("Hostname")
+        .to_string();
+    let domain = read_formula_file("dhcpd.json")["dhcpd"]["domain_name"].to_string();
+    let expected: Vec<String> = terminals
+        .members()
+        .map(|terminal| expected_minion_id(terminal, &naming, &domain))
+        .collect();
+    let step_time = support::read_env_or("UYUNI_TERMINAL_INTERVAL", 30);
+    let timeout = support::read_env_or("UYUNI_TERMINAL_TIMEOUT", 1800);
+    let step = time::Duration::from_secs(step_time);
+    let mut elapsed = 0;
+    /* Accept keys of expected terminals and wait for their registration */
+    let mut minions: Vec<Option<String>> = vec![None; expected.len()];
+    let mut system_ids: Vec<Option<i32>> = vec![None; expected.len()];
+    while system_ids.contains(&None) && elapsed < timeout {
+        for minion_id in support::list_pending_keys() {
+            for (index, expected_id) in expected.iter().enumerate() {
+                if minions[index].is_none() && minion_matches(&minion_id, expected_id, &naming) {
+                    support::accept_salt_key(&minion_id);
+                    minions[index] = Some(minion_id.clone());
+                }
+            }
+        }
+        for (index, minion_id) in minions.iter().enumerate() {
+            if let (Some(minion_id), None) = (minion_id, system_ids[index]) {
+                system_ids[index] = support::find_system_id(minion_id);
+                if let Some(system_id) = system_ids[index] {
+                    support::info(format!(
+                        "Terminal {} registered with id {}.",
+                        minion_id, system_id
+                    ));
+                }
+            }
+        }
+        if system_ids.contains(&None) {
+            support::info(format!(
+                "Waiting for registration of {} terminals after {} seconds.",
+                system_ids.iter().filter(|id| id.is_none()).count(),
+                elapsed
+            ));
+            thread::sleep(step);
+            elapsed += step_time;
+        }
+    }
+    /* Wait for saltboot states applied at registration */
+    let mut saltboot: Vec<Option<bool>> = vec![None; expected.len()];
+    while elapsed < timeout {
+        for (index, system_id) in system_ids.iter().enumerate() {
+            if let (Some(system_id), None) = (system_id, saltboot[index]) {
+                saltboot[index] = support::status_saltboot(*system_id);
+            }
+        }
+        let pending = system_ids
+            .iter()
+            .zip(saltboot.iter())
+            .filter(|(system_id, status)| system_id.is_some() && status.is_none())
+            .count();
+        if pending == 0 {
+            break;
+        }
+        support::info(format!(
+            "Saltboot is still running at {} terminals after {} seconds.",
+            pending, elapsed
+        ));
+        thread::sleep(step);
+        elapsed += step_time;
+    }
+    /* Verify terminals landed in hwtype and branch groups */
+    let branch_id = support::get_branch_id();
+    let mut report = String::new();
+    let mut failed = false;
+    for (index, terminal) in terminals.members().enumerate() {
+        let minion_id = minions[index]
+            .clone()
+            .unwrap_or_else(|| expected[index].clone());
+        let result = match (system_ids[index], saltboot[index]) {
+            (None, _) => "not registered".to_string(),
+            (Some(_), None) => "saltboot timed out".to_string(),
+            (Some(_), Some(false)) => "saltboot failed".to_string(),
+            (Some(system_id), Some(true)) => {
+                let groups = support::list_system_groups(system_id);
+                let missing: Vec<&str> = [terminal["hwtype"].as_str().unwrap_or(""), &branch_id]
+                    .iter()
+                    .filter(|group| !groups.contains(&group.to_string()))
+                    .cloned()
+                    .collect();
+                if missing.is_empty() {
+                    "ok".to_string()
+                } else {
+                    format!("missing groups {:?}", missing)
+                }
+            }
+        };
+        let line = format!("{:<40} {}", minion_id, result);
+        if result == "ok" {
+            support::info(line.clone());
+        } else {
+            support::error(line.clone());
+            failed = true;
+        }
+        report.push_str(&format!("{}\n", line));
+    }
+    support::save_report("report.log", &report);
+    if failed {
+        process::exit(1);
+    }
+}
+
+pub fn verify_terminals() {
+    support::info("STAGE Verification of terminals.".to_string());
+    let inventory = read_terminals();
+    let branch_id = support::get_branch_id();
+    let max_hours = support::read_env_or("UYUNI_MAX_CHECKIN_HOURS", 24) as i64;
+    let systems = support::list_systems();
+    let mut report = format!(
+        "{:<40} {:<12} {:<8} {:<12} {:<10} {}\n",
+        "TERMINAL", "REGISTERED", "GROUPS", "IMAGE", "CHECKIN", "RESULT"
+    );
+    let mut failed = false;
+    /* Terminal is system matching hostname pattern and MAC of inventory */
+    let mut matched: Vec<Option<(&xmlrpc::Value, bool)>> = Vec::new();
+    for terminal in inventory["terminals"].members() {
+        let pattern = match terminal["hostname"].as_str() {
+            Some(hostname) => hostname.to_string(),
+            None => terminal["name"].to_string(),
+        };
+        let mac = terminal["mac"].as_str().unwrap_or("").to_lowercase();
+        let candidates: Vec<&xmlrpc::Value> = systems
+            .iter()
+            .filter(|system| {
+                support::wildcard_match(&pattern, system["name"].as_str().unwrap_or(""))
+            })
+            .collect();
+        let with_mac = candidates.iter().find(|system| {
+            mac.is_empty()
+                || support::list_system_macs(system["id"].as_i32().unwrap()).contains(&mac)
+        });
+        matched.push(match (with_mac, candidates.first()) {
+            (Some(system), _) => Some((*system, true)),
+            (None, Some(system)) => Some((*system, false)),
+            (None, None) => None,
+        });
+    }
+    let system_ids: Vec<i32> = matched
+        .iter()
+        .flatten()
+        .map(|(system, _)| system["id"].as_i32().unwrap())
+        .collect();
+    let versions = support::terminal_image_versions(&system_ids);
+    for (terminal, system) in inventory["terminals"].members().zip(matched) {
+        let mut checks: Vec<(&str, bool)> = Vec::new();
+        let (registered, groups, image, checkin) = match system {
+            None => (
+                "no".to_string(),
+                "-".to_string(),
+                "-".to_string(),
+                "-".to_string(),
+            ),
+            Some((system, mac_ok)) => {
+                let system_id = system["id"].as_i32().unwrap();
+                checks.push(("mac", mac_ok));
+                let member_of = support::list_system_groups(system_id);
+                let groups_ok = [
+                    terminal["hwtype"].as_str().unwrap_or(""),
+                    &branch_id,
+                    "TERMINALS",
+                ]
+                .iter()
+                .all(|group| member_of.contains(&group.to_string()));
+                checks.push(("groups", groups_ok));
+                let version = versions.get(&system_id).cloned();
+                if let Some(expected) = terminal["image_version"].as_str() {
+                    checks.push(("image", version.as_deref() == Some(expected)));
+                }
+                let checkin = match support::value_to_datetime(&system["last_checkin"]) {
+                    Some(last_checkin) => {
+                        let age = chrono::Local::now().naive_local() - last_checkin;
+                        checks.push(("checkin", age.num_hours() < max_hours));
+                        format!("{}h ago", age.num_hours())
+                    }
+                    None => {
+                        checks.push(("checkin", false));
+                        "never".to_string()
+                    }
+                };
+                (
+                    "yes".to_string(),
+                    if groups_ok { "ok" } else { "missing" }.to_string(),
+                    version.unwrap_or_else(|| "unknown".to_string()),
+                    checkin,
+                )
+            }
+        };
+        let passed = system.is_some() && checks.iter().all(|(_, ok)| *ok);
+        let result = if passed {
+            "PASS".to_string()
+        } else {
+            let failed_checks: Vec<&str> = checks
+                .iter()
+                .filter(|(_, ok)| !ok)
+                .map(|(check, _)| *check)
+                .collect();
+            format!("FAIL {}", failed_checks.join(","))
+        };
+        let line = format!(
+            "{:<40} {:<12} {:<8} {:<12} {:<10} {}",
+            terminal["name"], registered, groups, image, checkin, result
+        );
+        if passed {
+            support::info(line.clone());
+        } else {
+            support::error(line.clone());
+            failed = true;
+        }
+        report.push_str(&format!("{}\n", line));
+    }
+    support::save_report("terminals-report.log", &report);
+    if failed {
+        process::exit(1);
+    }
+}
+
+pub fn bootstrap_servers() {
+    support::info("STAGE Bootstrap of branch server and build host.".to_string());
+    let hosts = json::parse(&support::read_text_file("bootstrap.json")).unwrap();
+    for host in hosts.members() {
+        let hostname = host["host"].to_string();
+        if support::find_system_id(&hostname).is_some() {
+            support::warning(format!("System {} is registered already.", hostname));
+        } else {
+            support::bootstrap_system(host);
+            let registered = support::wait_for_registration(
+                &hostname,
+                support::read_env_or("UYUNI_BOOTSTRAP_INTERVAL", 15),
+                support::read_env_or("UYUNI_BOOTSTRAP_TIMEOUT", 600),
+            );
+            if registered.is_none() {
+                support::error(format!("System {} did not register in time.", hostname));
+                process::exit(2);
+            }
+        }
+    }
+    /* Enable features of bootstrapped systems by their roles */
+    for host in hosts.members() {
+        let hostname = host["host"].to_string();
+        match host["role"].as_str() {
+            Some("branch") => {
+                activate_proxy(host);
+                configure_retail_formulas(&hostname);
+            }
+            Some("buildhost") => prepare_buildhost(&hostname),
+            Some(role) => support::warning(format!("Unknown role {} of {}.", role, host["host"])),
+            None => (),
+        }
+    }
+}
+
+fn activate_proxy(host: &JsonValue) {
+    /* Branch server becomes proxy by proxy_setup command of bootstrap.json run at it */
+    let hostname = host["host"].to_string();
+    let system_id = support::get_system_id(hostname.clone());
+    if support::is_proxy(system_id) {
+        support::info(format!("System {} is proxy already.", hostname));
+        return;
+    }
+    let command = host["proxy_setup"]
+        .as_str()
+        .unwrap_or("configure-proxy.sh --non-interactive --answer-file=/root/proxy-answers.txt");
+    let timeout = support::read_env_or("UYUNI_BOOTSTRAP_TIMEOUT", 600);
+    let action_id = support::schedule_script_run(
+        &[system_id],
+        &format!("#!/bin/sh\n{}\n", command),
+        timeout as i32,
+    );
+    support::info(format!("Activation of proxy at {} scheduled.", hostname));
+    let outcome = support::wait_for_action(
+        action_id,
+        &[system_id],
+        support::read_env_or("UYUNI_BOOTSTRAP_INTERVAL", 15),
+        timeout,
+    );
+    support::expect_action("Proxy activation", action_id, outcome);
+}
+
+fn teardown_object(object_type: &str, id: Option<i32>, name: &str, parent: Option<i32>) -> bool {
+    /* True when object was deleted or does not exist anymore */
+    match (object_type, parent) {
+        ("image", _) => {
+            let image_id = id.unwrap();
+            !support::exists_image(image_id) || support::delete_kiwi_image(image_id)
+        }
+        ("image_profile", _) => {
+            !support::exists_image_profile(name) || support::delete_image_profile(name)
+        }
+        ("image_store", _) => {
+            !support::exists_image_store(name) || support::delete_image_store(name) == 1
+        }
+        ("activation_key", _) => {
+            support::find_activation_key(name).is_none()
+                || support::delete_activation_key(name) == 1
+        }
+        ("group_formula", Some(group_id)) => {
+            !support::exists_system_group_id(group_id)
+                || support::remove_group_formula(group_id, name) == 1
+        }
+        ("system_formula", Some(system_id)) => support::remove_system_formula(system_id, name) == 1,
+        ("system_group", _) => {
+            !support::exists_system_group(name) || support::delete_system_group(name)
+        }
+        ("entitlement", _) => {
+            support::find_system_id(name).is_none()
+                || support::remove_buildhost_entitlement(name) == 1
+        }
+        ("custom_info_key", _) => {
+            !support::exists_custom_info_key(name) || support::delete_custom_info_key(name) == 1
+        }
+        _ => {
+            support::error(format!("Unknown created object {} {}.", object_type, name));
+            process::exit(1);
+        }
+    }
+}
+
+pub fn teardown() {
+    support::info("STAGE Teardown of objects created by the tool.".to_string());
+    /* Only objects recorded in created.json are removed, pre-existing ones are kept */
+    let created = support::read_created();
+    if created.is_empty() {
+        support::info("No created objects recorded, nothing to tear down.".to_string());
+        return;
+    }
+    for entry in created.members() {
+        support::info(format!(
+            "Created {} {} in run {} at {}.",
+            entry["type"], entry["name"], entry["run"], entry["timestamp"]
+        ));
+    }
+    support::info("Do you wish to delete all objects listed above? [y, n]".to_string());
+    if support::read_env("UYUNI_YES") != "yes" && !support::input().contains('y') {
+        process::exit(0);
+    }
+    /* Objects are removed in reverse order of creation, so dependent ones go first */
+    let mut failed = false;
+    for entry in created.members().rev() {
+        let object_type = entry["type"].as_str().unwrap();
+        let id = entry["id"].as_i32();
+        let name = entry["name"].as_str().unwrap();
+        let parent = entry["parent"].as_i32();
+        if teardown_object(object_type, id, name, parent) {
+            support::forget_created(object_type, id, name, parent);
+        } else {
+            support::error(format!(
+                "Deletion of {} {} failed, it is kept in ownership ledger.",
+                object_type, name
+            ));
+            failed = true;
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+    support::info("Teardown finished.".to_string());
 }
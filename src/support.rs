@@ -7,7 +7,8 @@ use json::JsonValue;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::env;
-use std::fs::File;
+use std::fs;
+use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
 use std::path::Path;
 use std::process;
@@ -23,16 +24,33 @@ pub fn call_server(xmlrpc_method: &str, key: Option<String>) -> Value {
     };
     debug(format!("Uyuni server {:?} called.", read_env("UYUNI_URL")));
     let result = request.call_url(read_env("UYUNI_URL"));
-    return result.unwrap();
+    result.unwrap()
 }
 
 pub fn read_env(env_variable: &str) -> String {
     env::var(env_variable).unwrap().to_string()
 }
 
+pub fn read_env_or(env_variable: &str, default: u64) -> u64 {
+    /* Optional numeric settings of config.json */
+    match env::var(env_variable) {
+        Ok(value) => match value.parse() {
+            Ok(number) => number,
+            Err(_) => {
+                error(format!(
+                    "Setting {} must be whole number, not {:?}.",
+                    env_variable, value
+                ));
+                process::exit(1);
+            }
+        },
+        Err(_) => default,
+    }
+}
+
 pub fn log(info: String, level: &str) {
     let now = chrono::Local::now();
-    let info_levels = vec!["INFO", "ERROR", "WARNING"];
+    let info_levels = ["INFO", "ERROR", "WARNING"];
     if !read_env("UYUNI_LOG_LEVEL").contains("NO")
         && (read_env("UYUNI_LOG_LEVEL").contains(level) || info_levels.contains(&level))
     {
@@ -69,8 +87,64 @@ pub fn read_text_file(text_file: &str) -> String {
         Err(reason) => {
             panic!("Cannot read file, because {:?}", reason);
         }
-        Ok(_) => return text_data,
+        Ok(_) => text_data,
+    }
+}
+
+pub fn write_text_file(text_file: &str, text: &str) {
+    let format_pathfile = format!("assets/{}/{}", read_env("UYUNI_PROFILE"), text_file);
+    match fs::write(&format_pathfile, text) {
+        Err(reason) => panic!("Cannot write file, because {:?}", reason),
+        Ok(_) => info(format!("File {:?} updated.", format_pathfile)),
+    };
+}
+
+pub fn exists_text_file(text_file: &str) -> bool {
+    let format_pathfile = format!("assets/{}/{}", read_env("UYUNI_PROFILE"), text_file);
+    Path::new(&format_pathfile).exists()
+}
+
+pub fn read_optional_json(json_file: &str, default: JsonValue) -> JsonValue {
+    if exists_text_file(json_file) {
+        return json::parse(&read_text_file(json_file)).unwrap();
+    }
+    default
+}
+
+pub fn fail_with_report(failures: &[String]) {
+    /* Failed checks are logged and saved to report of run, which then fails */
+    if failures.is_empty() {
+        return;
+    }
+    for failure in failures {
+        error(failure.to_string());
+    }
+    save_report("report.log", &(failures.join("\n") + "\n"));
+    process::exit(1);
+}
+
+pub fn save_report(report_file: &str, text: &str) {
+    /* Reports of run are stored in its own directory under runs */
+    let run_dir = read_env("UYUNI_RUN_DIR");
+    fs::create_dir_all(&run_dir).unwrap();
+    let pathfile = Path::new(&run_dir).join(report_file);
+    let mut file = match OpenOptions::new().create(true).append(true).open(&pathfile) {
+        Err(reason) => panic!("Cannot open file, because {:?}", reason),
+        Ok(file) => file,
     };
+    file.write_all(text.as_bytes()).unwrap();
+    info(format!("Report saved to {:?}.", pathfile.display()));
+}
+
+pub fn save_run_file(run_file: &str, text: &str) {
+    /* Generated files are replaced in directory of run, unlike reports appended to */
+    let run_dir = read_env("UYUNI_RUN_DIR");
+    fs::create_dir_all(&run_dir).unwrap();
+    let pathfile = Path::new(&run_dir).join(run_file);
+    if let Err(reason) = fs::write(&pathfile, text) {
+        panic!("Cannot write file, because {:?}", reason);
+    }
+    info(format!("File saved to {:?}.", pathfile.display()));
 }
 
 pub fn import_json_data(json_file: &str) -> HashMap<String, String> {
@@ -85,7 +159,7 @@ pub fn import_json_data(json_file: &str) -> HashMap<String, String> {
             json_value.to_string(),
         );
     }
-    return parsed_data;
+    parsed_data
 }
 
 pub fn json_array_to_xmlrpc(json_array: JsonValue) -> Vec<Value> {
@@ -99,7 +173,7 @@ pub fn json_array_to_xmlrpc(json_array: JsonValue) -> Vec<Value> {
             Value::Bool(false)
         });
     }
-    return map;
+    map
     // Approach to be considered: json_array.members().map(|item| json_to_btree(item));
 }
 
@@ -121,13 +195,13 @@ pub fn json_to_btree(parsed: &JsonValue) -> BTreeMap<String, Value> {
             },
         );
     }
-    return map;
+    map
 }
 
 pub fn input() -> String {
     let mut input_string = String::new();
     std::io::stdin().read_line(&mut input_string).unwrap();
-    return input_string;
+    input_string
 }
 
 pub fn get_system_id(system_name: String) -> i32 {
@@ -135,309 +209,2241 @@ pub fn get_system_id(system_name: String) -> i32 {
         .arg(read_env("UYUNI_KEY"))
         .arg(system_name)
         .call_url(read_env("UYUNI_URL"));
-    return req.unwrap()[0]["id"].as_i32().unwrap();
+    req.unwrap()[0]["id"].as_i32().unwrap()
 }
 
-pub fn exists_kiwi_profile() -> bool {
+pub fn list_image_stores() -> Vec<Value> {
+    let stores = call_server("image.store.listImageStores", Some(read_env("UYUNI_KEY")));
+    stores.as_array().unwrap().to_vec()
+}
+
+pub fn exists_image_store(label: &str) -> bool {
+    list_image_stores()
+        .iter()
+        .any(|store| store["label"].as_str() == Some(label))
+}
+
+pub fn create_image_store(store: &JsonValue) -> i32 {
+    /* Store is described as {"label", "uri", "type": "registry" | "os_image", "credentials"} */
+    let mut req = Request::new("image.store.create")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(store["label"].to_string())
+        .arg(store["uri"].to_string())
+        .arg(store["type"].to_string());
+    if store["credentials"].is_object() {
+        req = req.arg(Value::Struct(json_to_btree(&store["credentials"])));
+    }
+    let result = req.call_url(read_env("UYUNI_URL"));
+    info(format!(
+        "Image store {} of type {} created.",
+        store["label"], store["type"]
+    ));
+    let result = result.unwrap().as_i32().unwrap();
+    record_created("image_store", None, &store["label"].to_string(), None);
+    result
+}
+
+pub fn delete_image_store(label: &str) -> i32 {
+    if !may_delete("image_store", None, label, None) {
+        return 0;
+    }
+    let req = Request::new("image.store.delete")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(label)
+        .call_url(read_env("UYUNI_URL"));
+    let result = req.unwrap().as_i32().unwrap();
+    if result == 1 {
+        info(format!("Image store {} deleted.", label));
+        forget_created("image_store", None, label, None);
+    }
+    result
+}
+
+pub fn exists_image_profile(label: &str) -> bool {
     let profiles = call_server(
         "image.profile.listImageProfiles",
         Some(read_env("UYUNI_KEY")),
     );
     for profile in profiles.as_array().unwrap() {
-        if profile["label"]
-            .as_str()
-            .unwrap()
-            .contains(&read_env("UYUNI_KIWI_PROFILE"))
-        {
-            info(format!(
-                "Profile with name {} exists.",
-                read_env("UYUNI_KIWI_PROFILE")
-            ));
+        if profile["label"].as_str().unwrap() == label {
+            info(format!("Profile with name {} exists.", label));
             return true;
         }
     }
-    return false;
+    false
 }
 
-pub fn create_kiwi_profile() -> bool {
+pub fn get_image_profile_details(label: &str) -> Value {
+    let req = Request::new("image.profile.getDetails")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(label)
+        .call_url(read_env("UYUNI_URL"));
+    req.unwrap()
+}
+
+pub fn exists_kiwi_profile() -> bool {
+    exists_image_profile(&read_env("UYUNI_KIWI_PROFILE"))
+}
+
+pub fn create_image_profile(
+    label: &str,
+    image_type: &str,
+    store: &str,
+    path: &str,
+    activation_key: &str,
+) -> bool {
     let req = Request::new("image.profile.create")
         .arg(read_env("UYUNI_KEY"))
-        .arg(read_env("UYUNI_KIWI_PROFILE"))
-        .arg("kiwi")
-        .arg(read_env("UYUNI_IMAGE_STORE"))
-        .arg(read_env("UYUNI_PROFILE_PATH"))
-        .arg(read_env("UYUNI_ACTIVATION_KEY"))
+        .arg(label)
+        .arg(image_type)
+        .arg(store)
+        .arg(path)
+        .arg(activation_key)
         .call_url(read_env("UYUNI_URL"));
     if req.unwrap().as_i32().unwrap() == 1 {
         info(format!(
-            "Kiwi profile with name {} created.",
-            read_env("UYUNI_KIWI_PROFILE")
+            "Image profile {} of type {} created.",
+            label, image_type
         ));
+        record_created("image_profile", None, label, None);
         return true;
     }
-    return false;
+    false
 }
 
-pub fn delete_kiwi_profile() -> bool {
+pub fn create_kiwi_profile() -> bool {
+    create_image_profile(
+        &read_env("UYUNI_KIWI_PROFILE"),
+        "kiwi",
+        &read_env("UYUNI_IMAGE_STORE"),
+        &read_env("UYUNI_PROFILE_PATH"),
+        &read_env("UYUNI_ACTIVATION_KEY"),
+    )
+}
+
+pub fn delete_image_profile(label: &str) -> bool {
+    if !may_delete("image_profile", None, label, None) {
+        return false;
+    }
     let req = Request::new("image.profile.delete")
         .arg(read_env("UYUNI_KEY"))
-        .arg(read_env("UYUNI_KIWI_PROFILE"))
+        .arg(label)
         .call_url(read_env("UYUNI_URL"));
     if req.unwrap().as_i32().unwrap() == 1 {
-        info(format!(
-            "Kiwi profile with name {} deleted.",
-            read_env("UYUNI_KIWI_PROFILE")
-        ));
+        info(format!("Image profile with name {} deleted.", label));
+        forget_created("image_profile", None, label, None);
         return true;
     }
-    return false;
+    false
 }
 
-pub fn exists_kiwi_image() -> HashMap<bool, i32> {
-    let images = call_server("image.listImages", Some(read_env("UYUNI_KEY")));
-    let mut image_status = HashMap::new();
-    for image in images.as_array().unwrap() {
-        if image["name"]
-            .as_str()
-            .unwrap()
-            .contains(&read_env("UYUNI_KIWI_PROFILE"))
-        {
-            warning(format!(
-                "Image with name {} exists with unknown status.",
-                read_env("UYUNI_KIWI_PROFILE")
-            ));
-            image_status.insert(true, image["id"].as_i32().unwrap());
-            return image_status;
+pub fn delete_kiwi_profile() -> bool {
+    delete_image_profile(&read_env("UYUNI_KIWI_PROFILE"))
+}
+
+pub fn set_image_profile_details(
+    label: &str,
+    store: &str,
+    path: &str,
+    activation_key: &str,
+    kiwi_options: Option<&str>,
+) -> i32 {
+    let mut details: BTreeMap<String, Value> = BTreeMap::new();
+    details.insert("storeLabel".to_string(), Value::from(store));
+    details.insert("path".to_string(), Value::from(path));
+    details.insert("activationKey".to_string(), Value::from(activation_key));
+    if let Some(kiwi_options) = kiwi_options {
+        details.insert("kiwiOptions".to_string(), Value::from(kiwi_options));
+    }
+    let req = Request::new("image.profile.setDetails")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(label)
+        .arg(Value::Struct(details))
+        .call_url(read_env("UYUNI_URL"));
+    info(format!("Image profile with name {} updated.", label));
+    req.unwrap().as_i32().unwrap()
+}
+
+pub fn set_kiwi_profile_details(kiwi_options: &str) -> i32 {
+    set_image_profile_details(
+        &read_env("UYUNI_KIWI_PROFILE"),
+        &read_env("UYUNI_IMAGE_STORE"),
+        &read_env("UYUNI_PROFILE_PATH"),
+        &read_env("UYUNI_ACTIVATION_KEY"),
+        Some(kiwi_options),
+    )
+}
+
+pub fn ensure_custom_info_keys(keys: Vec<&str>) {
+    let existing = call_server("system.custominfo.listAllKeys", Some(read_env("UYUNI_KEY")));
+    let labels: Vec<&str> = existing
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|key| key["label"].as_str().unwrap_or(""))
+        .collect();
+    for key in keys {
+        if labels.contains(&key) {
+            continue;
         }
+        Request::new("system.custominfo.createKey")
+            .arg(read_env("UYUNI_KEY"))
+            .arg(key)
+            .arg(key)
+            .call_url(read_env("UYUNI_URL"))
+            .unwrap();
+        info(format!("Custom info key {} created.", key));
+        record_created("custom_info_key", None, key, None);
     }
-    image_status.insert(false, -1);
-    return image_status;
 }
 
-pub fn status_kiwi_image(image_id: i32) -> String {
-    let req = Request::new("image.getDetails")
+pub fn exists_custom_info_key(label: &str) -> bool {
+    let existing = call_server("system.custominfo.listAllKeys", Some(read_env("UYUNI_KEY")));
+    existing
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|key| key["label"].as_str() == Some(label))
+}
+
+pub fn delete_custom_info_key(label: &str) -> i32 {
+    if !may_delete("custom_info_key", None, label, None) {
+        return 0;
+    }
+    let req = Request::new("system.custominfo.deleteKey")
         .arg(read_env("UYUNI_KEY"))
-        .arg(image_id)
+        .arg(label)
         .call_url(read_env("UYUNI_URL"));
-    return req.unwrap()["buildStatus"].as_str().unwrap().to_string();
+    info(format!("Custom info key {} deleted.", label));
+    req.unwrap().as_i32().unwrap()
 }
 
-pub fn schedule_kiwi_image() -> i32 {
-    let now = iso8601::datetime(&chrono::offset::Utc::now().to_rfc3339()).unwrap();
-    let req = Request::new("image.scheduleImageBuild")
+pub fn set_kiwi_profile_custom_values(custom_info: &JsonValue) -> i32 {
+    ensure_custom_info_keys(custom_info.entries().map(|(key, _)| key).collect());
+    let mut values: BTreeMap<String, Value> = BTreeMap::new();
+    for (key, value) in custom_info.entries() {
+        values.insert(key.to_string(), Value::from(value.to_string()));
+    }
+    let req = Request::new("image.profile.setCustomValues")
         .arg(read_env("UYUNI_KEY"))
         .arg(read_env("UYUNI_KIWI_PROFILE"))
-        .arg("")
-        .arg(get_system_id(read_env("UYUNI_BUILD_HOST")))
-        .arg(Value::from(now))
+        .arg(Value::Struct(values))
         .call_url(read_env("UYUNI_URL"));
     info(format!(
-        "Building of image with name {} started.",
+        "Custom values of kiwi profile {} set.",
         read_env("UYUNI_KIWI_PROFILE")
     ));
-    return req.unwrap().as_i32().unwrap();
+    req.unwrap().as_i32().unwrap()
 }
 
-pub fn delete_kiwi_image(image_id: i32) -> bool {
-    let req = Request::new("image.delete")
-        .arg(read_env("UYUNI_KEY"))
-        .arg(image_id)
-        .call_url(read_env("UYUNI_URL"));
-    if req.unwrap().as_i32().unwrap() == 1 {
-        info(format!("Kiwi image with id {} deleted.", image_id));
-        return true;
-    }
-    return false;
+#[derive(Debug, Clone)]
+pub struct ImageRecord {
+    pub id: i32,
+    pub name: String,
+    pub version: String,
+    pub revision: i32,
+    pub status: String,
 }
 
-pub fn has_buildhost_entitlement() -> bool {
-    let entitlements = Request::new("system.getEntitlements")
-        .arg(read_env("UYUNI_KEY"))
-        .arg(get_system_id(read_env("UYUNI_BUILD_HOST")))
-        .call_url(read_env("UYUNI_URL"));
-    for entitlement in entitlements.unwrap().as_array().unwrap() {
-        if entitlement.as_str().unwrap().contains("osimage_build_host") {
-            warning(format!(
-                "Buildhost entitlement was set already for {}.",
-                read_env("UYUNI_BUILD_HOST")
-            ));
-            return true;
-        }
+pub fn split_image_name_version(kiwi_initrd: &str) -> (String, String) {
+    /* kiwi_initrd is composed as <image name>-<version> */
+    match kiwi_initrd.rfind('-') {
+        Some(index) => (
+            kiwi_initrd[..index].to_string(),
+            kiwi_initrd[index + 1..].to_string(),
+        ),
+        None => (kiwi_initrd.to_string(), String::new()),
     }
-    return false;
 }
 
-pub fn add_buildhost_entitlement() -> i32 {
-    let req = Request::new("system.addEntitlements")
-        .arg(read_env("UYUNI_KEY"))
-        .arg(get_system_id(read_env("UYUNI_BUILD_HOST")))
-        .arg(Value::Array(vec![Value::from("osimage_build_host")]))
-        .call_url(read_env("UYUNI_URL"));
-    info(format!(
-        "Buildhost entitlement set for {}.",
-        read_env("UYUNI_BUILD_HOST")
-    ));
-    return req.unwrap().as_i32().unwrap();
+pub fn kiwi_image_name_version() -> (String, String) {
+    split_image_name_version(&read_env("UYUNI_KIWI_INITRD"))
 }
 
-pub fn schedule_highstate(system_name: String) -> i32 {
-    let now = iso8601::datetime(&chrono::offset::Utc::now().to_rfc3339()).unwrap();
-    let req = Request::new("system.scheduleApplyHighstate")
-        .arg(read_env("UYUNI_KEY"))
-        .arg(get_system_id(system_name.clone()))
-        .arg(Value::from(now))
-        .arg(false)
-        .call_url(read_env("UYUNI_URL"));
-    info(format!(
-        "Highstate for system {} scheduled (patience please).",
-        system_name
-    ));
-    return req.unwrap().as_i32().unwrap();
+pub fn list_images(label: &str, name: &str, version: &str) -> Vec<ImageRecord> {
+    let images = call_server("image.listImages", Some(read_env("UYUNI_KEY")));
+    matching_images(&images, label, name, version)
 }
 
-pub fn status_highstate(system_name: String, id: i32) -> i32 {
-    let events = Request::new("system.listSystemEvents")
-        .arg(read_env("UYUNI_KEY"))
-        .arg(get_system_id(system_name.clone()))
-        .call_url(read_env("UYUNI_URL"));
-    for event in events.unwrap().as_array().unwrap() {
-        if event["id"].as_i32().unwrap() == id {
-            let failed = event["failed_count"].as_i32().unwrap();
-            let success = event["successful_count"].as_i32().unwrap();
-            if failed == 0 && success > 0 {
-                return 1; // Success
-            } else if failed == 1 {
-                return -1; // Failure
-            } else {
-                return 0; // We don't know yet
-            }
+fn matching_images(images: &Value, label: &str, name: &str, version: &str) -> Vec<ImageRecord> {
+    /* Images of profile sorted by revision, pending builds without profile are matched by name */
+    let mut records: Vec<ImageRecord> = Vec::new();
+    for image in images.as_array().unwrap() {
+        let record = ImageRecord {
+            id: image["id"].as_i32().unwrap(),
+            name: image["name"].as_str().unwrap_or("").to_string(),
+            version: image["version"].as_str().unwrap_or("").to_string(),
+            revision: image["revision"].as_i32().unwrap_or(0),
+            status: image["buildStatus"].as_str().unwrap_or("").to_string(),
+        };
+        let by_label = match image["profileLabel"].as_str() {
+            Some(profile) if !profile.is_empty() => profile == label,
+            _ => record.name == label || record.name == name,
+        };
+        if by_label && (record.version.is_empty() || record.version == version) {
+            debug(format!("Image {:?} matches profile {}.", record, label));
+            records.push(record);
         }
     }
-    return 0;
+    records.sort_by_key(|record| record.revision);
+    records
 }
 
-pub fn wait_for_highstate(system_name: &str, event_id: i32, limit: u64, step_time: u64) {
-    let step = time::Duration::from_secs(step_time);
-    for i in 1..limit {
-        thread::sleep(step);
-        let status = status_highstate(system_name.to_string(), event_id);
-        match status {
-            0 => info(format!(
-                "Highstate is still running after {} seconds.",
-                i * step_time
-            )),
-            1 => {
-                info(format!(
-                    "Highstate was successfull after {} seconds.",
-                    i * step_time
+pub fn list_kiwi_images() -> Vec<ImageRecord> {
+    let (name, version) = kiwi_image_name_version();
+    list_images(&read_env("UYUNI_KIWI_PROFILE"), &name, &version)
+}
+
+pub fn prune_kiwi_images(images: &[ImageRecord]) {
+    /* Policy for older revisions of image: keep (default), delete or number of kept revisions */
+    let policy = env::var("UYUNI_OLD_REVISIONS").unwrap_or_else(|_| "keep".to_string());
+    let kept = match policy.as_str() {
+        "keep" => images.len(),
+        "delete" => 1,
+        _ => match policy.parse::<usize>() {
+            Ok(kept) if kept > 0 => kept,
+            _ => {
+                error(format!(
+                    "Unknown policy {:?} for older image revisions.",
+                    policy
                 ));
-                break;
-            }
-            -1 => {
-                error(format!("Highstate failed after {} seconds.", i * step_time));
                 process::exit(1);
             }
-            _ => warning(format!(
-                "Better not to imagine what happened with highstate."
-            )),
-        }
+        },
+    };
+    debug(format!(
+        "Last {} revisions of image are kept: {:?}",
+        kept, images
+    ));
+    for image in images.iter().rev().skip(kept) {
+        delete_kiwi_image(image.id);
     }
 }
 
-pub fn create_system_group(group_name: &str) -> i32 {
-    let req = Request::new("systemgroup.create")
-        .arg(read_env("UYUNI_KEY"))
-        .arg(group_name)
-        .arg(group_name)
-        .call_url(read_env("UYUNI_URL"));
-    info(format!("System group {} created.", group_name));
-    return req.unwrap()["id"].as_i32().unwrap();
+pub fn read_state(state_file: &str) -> String {
+    /* State of previous runs is stored per environment under state directory */
+    let pathfile = format!("state/{}/{}", read_env("UYUNI_PROFILE"), state_file);
+    fs::read_to_string(pathfile).unwrap_or_default()
 }
 
-pub fn delete_system_group(group_name: &str) -> bool {
-    let req = Request::new("systemgroup.delete")
-        .arg(read_env("UYUNI_KEY"))
-        .arg(group_name)
-        .call_url(read_env("UYUNI_URL"));
-    if req.unwrap().as_i32().unwrap() == 1 {
-        info(format!("System group with name {} deleted.", group_name));
+pub fn write_state(state_file: &str, text: &str) {
+    let state_dir = format!("state/{}", read_env("UYUNI_PROFILE"));
+    fs::create_dir_all(&state_dir).unwrap();
+    fs::write(Path::new(&state_dir).join(state_file), text).unwrap();
+    debug(format!("State {} saved.", state_file));
+}
+
+pub fn read_created() -> JsonValue {
+    /* Ownership ledger of objects created by the tool, in order of creation */
+    let text = read_state("created.json");
+    if text.is_empty() {
+        return JsonValue::new_array();
+    }
+    json::parse(&text).unwrap()
+}
+
+fn is_same_object(
+    entry: &JsonValue,
+    object_type: &str,
+    id: Option<i32>,
+    name: &str,
+    parent: Option<i32>,
+) -> bool {
+    /* Objects with id are matched by it, because e.g. image names repeat across revisions */
+    if entry["type"] != object_type || entry["parent"].as_i32() != parent {
+        return false;
+    }
+    match id {
+        Some(id) => entry["id"].as_i32() == Some(id),
+        None => entry["name"] == name,
+    }
+}
+
+pub fn record_created(object_type: &str, id: Option<i32>, name: &str, parent: Option<i32>) {
+    /* Parent is id of group or system the formula is assigned to */
+    let mut created = read_created();
+    if created
+        .members()
+        .any(|entry| is_same_object(entry, object_type, id, name, parent))
+    {
+        return;
+    }
+    let run_dir = read_env("UYUNI_RUN_DIR");
+    let mut entry = json::object! {
+        "type" => object_type,
+        "name" => name,
+        "run" => Path::new(&run_dir).file_name().unwrap().to_str().unwrap(),
+        "timestamp" => chrono::Local::now().to_rfc3339()
+    };
+    if let Some(id) = id {
+        entry["id"] = id.into();
+    }
+    if let Some(parent) = parent {
+        entry["parent"] = parent.into();
+    }
+    created.push(entry).unwrap();
+    write_state("created.json", &json::stringify_pretty(created, 2));
+}
+
+pub fn forget_created(object_type: &str, id: Option<i32>, name: &str, parent: Option<i32>) {
+    let created: Vec<JsonValue> = read_created()
+        .members()
+        .filter(|entry| !is_same_object(entry, object_type, id, name, parent))
+        .cloned()
+        .collect();
+    write_state(
+        "created.json",
+        &json::stringify_pretty(JsonValue::Array(created), 2),
+    );
+}
+
+fn owns(object_type: &str, id: Option<i32>, name: &str, parent: Option<i32>) -> bool {
+    /* Objects not created by the tool are changed only with --force */
+    read_env("UYUNI_FORCE") == "yes"
+        || read_created()
+            .members()
+            .any(|entry| is_same_object(entry, object_type, id, name, parent))
+}
+
+fn may_delete(object_type: &str, id: Option<i32>, name: &str, parent: Option<i32>) -> bool {
+    if owns(object_type, id, name, parent) {
         return true;
     }
-    return false;
+    warning(format!(
+        "{} {} was not created by the tool, pass --force to delete it.",
+        object_type,
+        match id {
+            Some(id) => id.to_string(),
+            None => name.to_string(),
+        }
+    ));
+    false
 }
 
-pub fn exists_system_group(group_name: &str) -> bool {
-    let system_groups = call_server("systemgroup.listAllGroups", Some(read_env("UYUNI_KEY")));
-    for system_group in system_groups.as_array().unwrap() {
-        if system_group["name"].as_str().unwrap().contains(&group_name) {
-            warning(format!(
-                "System_group with name {:?} exists.",
-                system_group["name"].as_str().unwrap()
-            ));
-            return true;
+pub fn kiwi_source_revision(profile_path: &str) -> String {
+    /* profile_path is composed as <git url>#<branch>:<directory> */
+    let (url, reference) = match profile_path.split_once('#') {
+        Some((url, rest)) => (url, rest.split(':').next().unwrap_or("HEAD")),
+        None => (profile_path, "HEAD"),
+    };
+    let output = match process::Command::new("git")
+        .args(["ls-remote", url, reference])
+        .output()
+    {
+        Ok(output) => output,
+        Err(reason) => {
+            error(format!("Cannot run git ls-remote, because {:?}", reason));
+            process::exit(1);
         }
-    }
-    return false;
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout.split_whitespace().next().unwrap_or("").to_string()
+}
+
+pub fn kiwi_source_changed(label: &str, revision: &str) -> bool {
+    let previous = read_state(&format!("kiwi-source-{}", label));
+    debug(format!(
+        "Kiwi profile {} source revision {:?}, previously built {:?}.",
+        label, revision, previous
+    ));
+    revision.is_empty() || previous.trim() != revision
+}
+
+pub fn save_kiwi_source(label: &str, revision: &str) {
+    write_state(&format!("kiwi-source-{}", label), revision);
 }
 
-pub fn set_saltboot_formula(group_id: i32) -> i32 {
-    let formula = Request::new("formula.setFormulasOfGroup")
+pub fn get_image_details(image_id: i32) -> Value {
+    let req = Request::new("image.getDetails")
         .arg(read_env("UYUNI_KEY"))
-        .arg(group_id)
-        .arg(Value::Array(vec![Value::from("saltboot")]))
-        .call_url(read_env("UYUNI_URL"));
-    if formula.unwrap().as_i32().unwrap() == 1 {
-        /* Parse data from json file and map it to XMLRPC data types */
-        let json_data = read_text_file("saltboot.json");
-        let parsed = &json::parse(&json_data).unwrap();
-        debug(format!("{:?}", json_to_btree(parsed)));
-        let data = Request::new("formula.setGroupFormulaData")
+        .arg(image_id)
+        .call_url(read_env("UYUNI_URL"));
+    req.unwrap()
+}
+
+pub fn status_kiwi_image(image_id: i32) -> String {
+    get_image_details(image_id)["buildStatus"]
+        .as_str()
+        .unwrap()
+        .to_string()
+}
+
+pub fn wait_for_image_inspection(image_id: i32, step_time: u64, timeout: u64) -> Value {
+    let step = time::Duration::from_secs(step_time);
+    let mut elapsed = 0;
+    loop {
+        let details = Request::new("image.getDetails")
             .arg(read_env("UYUNI_KEY"))
-            .arg(group_id)
-            .arg("saltboot")
-            .arg(Value::Struct(json_to_btree(parsed)))
-            .call_url(read_env("UYUNI_URL"));
-        info("Saltboot formula cofigured.".to_string());
-        return data.unwrap().as_i32().unwrap();
-    } else {
-        return -1;
+            .arg(image_id)
+            .call_url(read_env("UYUNI_URL"))
+            .unwrap();
+        let status = details["inspectStatus"].as_str().unwrap_or("").to_string();
+        info(format!(
+            "Inspection of image {} is *{}* after {} seconds.",
+            image_id, status, elapsed
+        ));
+        if status == "completed" || status == "failed" || elapsed >= timeout {
+            return details;
+        }
+        thread::sleep(step);
+        elapsed += step_time;
     }
 }
 
-pub fn set_system_formulas(system_id: i32, formulas: Vec<&str>) -> i32 {
-    let mut formula_names: Vec<Value> = Vec::new();
-    for formula in formulas {
-        formula_names.push(Value::from(formula));
-    }
-    let req = Request::new("formula.setFormulasOfServer")
+pub fn get_pillar(system_id: i32, category: &str) -> Result<Value, String> {
+    /* Without category server returns only custom pillar of minion */
+    let minion_id = get_system_details(system_id)["minion_id"]
+        .as_str()
+        .unwrap_or("")
+        .to_string();
+    let pillar = match Request::new("system.getPillar")
         .arg(read_env("UYUNI_KEY"))
-        .arg(system_id)
-        .arg(Value::Array(formula_names))
-        .call_url(read_env("UYUNI_URL"));
-    info("All formulas enabled for system, but not configured yet.".to_string());
-    return req.unwrap().as_i32().unwrap();
+        .arg(minion_id.as_str())
+        .arg(category)
+        .call_url(read_env("UYUNI_URL"))
+    {
+        Ok(pillar) => pillar,
+        Err(reason) => {
+            return Err(format!(
+                "Pillar category {} of system {} cannot be read: {}",
+                category, minion_id, reason
+            ))
+        }
+    };
+    match pillar.as_struct() {
+        Some(data) if !data.is_empty() => Ok(pillar),
+        _ => Err(format!(
+            "Pillar category {} of system {} is missing.",
+            category, minion_id
+        )),
+    }
 }
 
-pub fn set_system_formula_data(system_id: i32, formula_name: &str) -> i32 {
-    let json_data = read_text_file(format!("{}.json", formula_name).as_str());
-    let parsed = &json::parse(&json_data).unwrap();
-    debug(format!("{:?}", json_to_btree(parsed)));
-    let data = Request::new("formula.setSystemFormulaData")
+pub fn image_in_pillar(system_id: i32, name: &str, version: &str) -> Result<bool, String> {
+    /* Images synchronized by branch server are listed in images of its image pillar category */
+    let category = env::var("UYUNI_IMAGE_PILLAR").unwrap_or_else(|_| "images".to_string());
+    let pillar = get_pillar(system_id, &category)?;
+    if let Some(versions) = pillar["images"][name].as_struct() {
+        return Ok(versions.keys().any(|key| key.starts_with(version)));
+    }
+    Ok(false)
+}
+
+pub fn verify_image_inspection(image_id: i32) -> (Value, Vec<String>) {
+    let mut failures: Vec<String> = Vec::new();
+    let details = wait_for_image_inspection(
+        image_id,
+        read_env_or("UYUNI_INSPECT_INTERVAL", 30),
+        read_env_or("UYUNI_INSPECT_TIMEOUT", 900),
+    );
+    if details["inspectStatus"].as_str() != Some("completed") {
+        failures.push(format!(
+            "Inspection of image {} is not completed: {:?}.",
+            image_id, details["inspectStatus"]
+        ));
+    }
+    if details["installedPackages"].as_i32().unwrap_or(0) < 1 {
+        failures.push(format!("Image {} has no packages listed.", image_id));
+    }
+    (details, failures)
+}
+
+pub fn verify_kiwi_image(image_id: i32, kiwi_initrd: &str) -> Vec<String> {
+    let (details, mut failures) = verify_image_inspection(image_id);
+    let (name, version) = split_image_name_version(kiwi_initrd);
+    if details["name"].as_str() != Some(&name) || details["version"].as_str() != Some(&version) {
+        failures.push(format!(
+            "Image {:?}-{:?} does not match kiwi_initrd {}.",
+            details["name"], details["version"], kiwi_initrd
+        ));
+    }
+    let rbs_id = get_system_id(read_env("UYUNI_BRANCH_SERVER"));
+    match image_in_pillar(rbs_id, &name, &version) {
+        Ok(true) => {}
+        Ok(false) => failures.push(format!(
+            "Image {} is not in pillar of branch server {}.",
+            kiwi_initrd,
+            read_env("UYUNI_BRANCH_SERVER")
+        )),
+        Err(reason) => failures.push(reason),
+    }
+    failures
+}
+
+pub fn schedule_image_build(label: &str, version: &str, build_host: &str) -> i32 {
+    let now = iso8601::datetime(&chrono::offset::Utc::now().to_rfc3339()).unwrap();
+    let req = Request::new("image.scheduleImageBuild")
         .arg(read_env("UYUNI_KEY"))
-        .arg(system_id)
-        .arg(formula_name)
-        .arg(Value::Struct(json_to_btree(parsed)))
+        .arg(label)
+        .arg(version)
+        .arg(get_system_id(build_host.to_string()))
+        .arg(Value::from(now))
         .call_url(read_env("UYUNI_URL"));
-    info(format!("*{:?}* formula cofigured.", formula_name));
-    return data.unwrap().as_i32().unwrap();
+    info(format!("Building of image with name {} started.", label));
+    let action_id = req.unwrap().as_i32().unwrap();
+    /* Pending image is recorded before build finishes or fails */
+    match find_image_of_action(label, action_id) {
+        Some(image_id) => record_created("image", Some(image_id), label, None),
+        None => warning(format!(
+            "Pending image of profile {} built by action {} not found.",
+            label, action_id
+        )),
+    }
+    action_id
 }
 
-pub fn clone_activation_key(key_name: &str) -> String {
+fn find_image_of_action(label: &str, action_id: i32) -> Option<i32> {
+    /* Image belongs to build when its build action is the scheduled one, newest images first */
+    let images = call_server("image.listImages", Some(read_env("UYUNI_KEY")));
+    let mut candidates: Vec<&Value> = images
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|image| {
+            image["profileLabel"]
+                .as_str()
+                .is_none_or(|profile| profile.is_empty() || profile == label)
+        })
+        .collect();
+    candidates.sort_by_key(|image| std::cmp::Reverse(image["id"].as_i32()));
+    for image in candidates {
+        let image_id = image["id"].as_i32()?;
+        let build_action = match image["buildActionId"].as_i32() {
+            Some(build_action) => Some(build_action),
+            None => get_image_details(image_id)["buildActionId"].as_i32(),
+        };
+        if build_action == Some(action_id) {
+            return Some(image_id);
+        }
+    }
+    None
+}
+
+pub fn delete_kiwi_image(image_id: i32) -> bool {
+    if !may_delete("image", Some(image_id), "", None) {
+        return false;
+    }
+    let req = Request::new("image.delete")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(image_id)
+        .call_url(read_env("UYUNI_URL"));
+    if req.unwrap().as_i32().unwrap() == 1 {
+        info(format!("Kiwi image with id {} deleted.", image_id));
+        forget_created("image", Some(image_id), "", None);
+        return true;
+    }
+    false
+}
+
+pub fn exists_image(image_id: i32) -> bool {
+    let images = call_server("image.listImages", Some(read_env("UYUNI_KEY")));
+    images
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|image| image["id"].as_i32() == Some(image_id))
+}
+
+pub fn value_to_datetime(value: &Value) -> Option<chrono::NaiveDateTime> {
+    /* Server returns date times in its local time without time zone */
+    if let Value::DateTime(datetime) = value {
+        if let iso8601::Date::YMD { year, month, day } = datetime.date {
+            return chrono::NaiveDate::from_ymd_opt(year, month, day)?.and_hms_opt(
+                datetime.time.hour,
+                datetime.time.minute,
+                datetime.time.second,
+            );
+        }
+    }
+    None
+}
+
+pub fn get_system_details(system_id: i32) -> Value {
+    let req = Request::new("system.getDetails")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(system_id)
+        .call_url(read_env("UYUNI_URL"));
+    req.unwrap()
+}
+
+pub fn list_system_channels(system_id: i32) -> Vec<Value> {
+    /* Base channel first, then child channels */
+    let mut channels: Vec<Value> = Vec::new();
+    let base = Request::new("system.getSubscribedBaseChannel")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(system_id)
+        .call_url(read_env("UYUNI_URL"))
+        .unwrap();
+    channels.push(base);
+    let children = Request::new("system.listSubscribedChildChannels")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(system_id)
+        .call_url(read_env("UYUNI_URL"))
+        .unwrap();
+    channels.extend(children.as_array().unwrap().iter().cloned());
+    channels
+}
+
+pub fn missing_channels(channels: &[Value], required: &[String]) -> Vec<String> {
+    /* Required channels are matched case insensitive by part of name or label */
+    required
+        .iter()
+        .filter(|channel| {
+            !channels.iter().any(|subscribed| {
+                let name = subscribed["name"].as_str().unwrap_or("").to_lowercase();
+                let label = subscribed["label"].as_str().unwrap_or("").to_lowercase();
+                name.contains(&channel.to_lowercase()) || label.contains(&channel.to_lowercase())
+            })
+        })
+        .cloned()
+        .collect()
+}
+
+pub fn get_activation_key_details(key_name: &str) -> Value {
+    let req = Request::new("activationkey.getDetails")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(key_name)
+        .call_url(read_env("UYUNI_URL"));
+    req.unwrap()
+}
+
+pub fn check_buildhost(
+    build_host: &str,
+    activation_key: &str,
+    settings: &JsonValue,
+) -> Vec<String> {
+    let mut failures: Vec<String> = Vec::new();
+    let system_id = get_system_id(build_host.to_string());
+    let details = get_system_details(system_id);
+    if !details["base_entitlement"]
+        .as_str()
+        .unwrap_or("")
+        .contains("salt")
+    {
+        failures.push(format!(
+            "Buildhost {} is not salt minion ({:?}).",
+            build_host, details["base_entitlement"]
+        ));
+    }
+    let max_hours = settings["max_checkin_hours"].as_i64().unwrap_or(24);
+    match value_to_datetime(&details["last_checkin"]) {
+        Some(last_checkin) => {
+            let age = chrono::Local::now().naive_local() - last_checkin;
+            if age.num_hours() >= max_hours {
+                failures.push(format!(
+                    "Buildhost {} did not check in for {} hours (last checkin {}).",
+                    build_host,
+                    age.num_hours(),
+                    last_checkin
+                ));
+            }
+        }
+        None => failures.push(format!("Buildhost {} never checked in.", build_host)),
+    }
+    let channels = list_system_channels(system_id);
+    let required: Vec<String> = if settings["required_channels"].is_array() {
+        settings["required_channels"]
+            .members()
+            .map(|channel| channel.to_string())
+            .collect()
+    } else {
+        vec!["Containers".to_string(), "Development Tools".to_string()]
+    };
+    for channel in missing_channels(&channels, &required) {
+        failures.push(format!(
+            "Buildhost {} is not subscribed to {} channel.",
+            build_host, channel
+        ));
+    }
+    if activation_key.is_empty() {
+        /* Image profile without activation key builds with channels of buildhost */
+        debug(format!(
+            "No activation key to compare with channels of buildhost {}.",
+            build_host
+        ));
+        return failures;
+    }
+    let key = get_activation_key_details(activation_key);
+    let base_label = channels[0]["label"].as_str().unwrap_or("");
+    if key["base_channel_label"].as_str().unwrap_or("") != base_label {
+        failures.push(format!(
+            "Base channel {:?} of activation key {} does not match base channel {:?} of buildhost.",
+            key["base_channel_label"], activation_key, base_label
+        ));
+    }
+    for child in key["child_channel_labels"].as_array().unwrap_or(&[]) {
+        if !channels.iter().any(|channel| channel["label"] == *child) {
+            warning(format!(
+                "Child channel {:?} of activation key {} is not subscribed at buildhost.",
+                child, activation_key
+            ));
+        }
+    }
+    failures
+}
+
+pub fn has_buildhost_entitlement(build_host: &str) -> bool {
+    let entitlements = Request::new("system.getEntitlements")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(get_system_id(build_host.to_string()))
+        .call_url(read_env("UYUNI_URL"));
+    for entitlement in entitlements.unwrap().as_array().unwrap() {
+        if entitlement.as_str().unwrap().contains("osimage_build_host") {
+            warning(format!(
+                "Buildhost entitlement was set already for {}.",
+                build_host
+            ));
+            return true;
+        }
+    }
+    false
+}
+
+pub fn add_buildhost_entitlement(build_host: &str) -> i32 {
+    let req = Request::new("system.addEntitlements")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(get_system_id(build_host.to_string()))
+        .arg(Value::Array(vec![Value::from("osimage_build_host")]))
+        .call_url(read_env("UYUNI_URL"));
+    info(format!("Buildhost entitlement set for {}.", build_host));
+    let result = req.unwrap().as_i32().unwrap();
+    record_created("entitlement", None, build_host, None);
+    result
+}
+
+pub fn remove_buildhost_entitlement(build_host: &str) -> i32 {
+    if !may_delete("entitlement", None, build_host, None) {
+        return 0;
+    }
+    let req = Request::new("system.removeEntitlements")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(get_system_id(build_host.to_string()))
+        .arg(Value::Array(vec![Value::from("osimage_build_host")]))
+        .call_url(read_env("UYUNI_URL"));
+    info(format!(
+        "Buildhost entitlement removed from system {}.",
+        build_host
+    ));
+    req.unwrap().as_i32().unwrap()
+}
+
+pub fn schedule_highstate(system_name: String) -> i32 {
+    let now = iso8601::datetime(&chrono::offset::Utc::now().to_rfc3339()).unwrap();
+    let req = Request::new("system.scheduleApplyHighstate")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(get_system_id(system_name.clone()))
+        .arg(Value::from(now))
+        .arg(false)
+        .call_url(read_env("UYUNI_URL"));
+    info(format!(
+        "Highstate for system {} scheduled (patience please).",
+        system_name
+    ));
+    req.unwrap().as_i32().unwrap()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ActionOutcome {
+    Completed,
+    Failed(Vec<i32>),   // Systems where action failed
+    TimedOut(Vec<i32>), // Systems where action is still pending
+}
+
+pub fn list_action_systems(xmlrpc_method: &str, action_id: i32) -> Vec<i32> {
+    let req = Request::new(xmlrpc_method)
+        .arg(read_env("UYUNI_KEY"))
+        .arg(action_id)
+        .call_url(read_env("UYUNI_URL"));
+    let mut system_ids: Vec<i32> = Vec::new();
+    for system in req.unwrap().as_array().unwrap() {
+        system_ids.push(system["server_id"].as_i32().unwrap());
+    }
+    system_ids
+}
+
+pub fn wait_for_action(
+    action_id: i32,
+    system_ids: &[i32],
+    step_time: u64,
+    timeout: u64,
+) -> ActionOutcome {
+    /* Poll action until it is finished at all systems or timeout is reached */
+    let step = time::Duration::from_secs(step_time);
+    let mut elapsed = 0;
+    let mut pending: Vec<i32> = system_ids.to_vec();
+    while elapsed < timeout {
+        thread::sleep(step);
+        elapsed += step_time;
+        let completed = list_action_systems("schedule.listCompletedSystems", action_id);
+        let failed: Vec<i32> = list_action_systems("schedule.listFailedSystems", action_id)
+            .into_iter()
+            .filter(|id| system_ids.contains(id))
+            .collect();
+        pending = system_ids
+            .iter()
+            .filter(|id| !completed.contains(id) && !failed.contains(id))
+            .cloned()
+            .collect();
+        if !pending.is_empty() {
+            info(format!(
+                "Action {} is still running at {} of {} systems after {} seconds.",
+                action_id,
+                pending.len(),
+                system_ids.len(),
+                elapsed
+            ));
+        } else if !failed.is_empty() {
+            error(format!(
+                "Action {} failed at systems {:?} after {} seconds.",
+                action_id, failed, elapsed
+            ));
+            return ActionOutcome::Failed(failed);
+        } else {
+            info(format!(
+                "Action {} completed after {} seconds.",
+                action_id, elapsed
+            ));
+            return ActionOutcome::Completed;
+        }
+    }
+    ActionOutcome::TimedOut(pending)
+}
+
+pub fn expect_action(action_name: &str, action_id: i32, outcome: ActionOutcome) {
+    match outcome {
+        ActionOutcome::Completed => info(format!("{} was successfull.", action_name)),
+        ActionOutcome::Failed(system_ids) => {
+            error(format!(
+                "{} failed at systems {:?}.",
+                action_name, system_ids
+            ));
+            report_action_failure(action_name, action_id, &system_ids);
+            process::exit(1);
+        }
+        ActionOutcome::TimedOut(system_ids) => {
+            error(format!(
+                "{} did not finish in time at systems {:?}.",
+                action_name, system_ids
+            ));
+            process::exit(2);
+        }
+    }
+}
+
+pub fn get_system_event(system_id: i32, event_id: i32) -> Value {
+    let events = Request::new("system.listSystemEvents")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(system_id)
+        .call_url(read_env("UYUNI_URL"));
+    for event in events.unwrap().as_array().unwrap() {
+        if event["id"].as_i32().unwrap() == event_id {
+            return event.clone();
+        }
+    }
+    Value::Nil
+}
+
+pub fn failed_salt_states(output: &JsonValue) -> Vec<String> {
+    /* Walk salt return and collect states with result false and their comments */
+    let mut failed: Vec<String> = Vec::new();
+    for (state_id, state) in output.entries() {
+        if state["result"] == false {
+            failed.push(format!("{}: {}", state_id, state["comment"]));
+        } else if state.is_object() && !state.has_key("result") {
+            failed.append(&mut failed_salt_states(state));
+        }
+    }
+    failed
+}
+
+pub fn report_action_failure(action_name: &str, action_id: i32, system_ids: &[i32]) {
+    let failed_systems = Request::new("schedule.listFailedSystems")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(action_id)
+        .call_url(read_env("UYUNI_URL"))
+        .unwrap();
+    for system_id in system_ids {
+        let mut report = format!(
+            "{} (action {}) failed at system {}.\n",
+            action_name, action_id, system_id
+        );
+        for failed_system in failed_systems.as_array().unwrap() {
+            if failed_system["server_id"].as_i32() == Some(*system_id) {
+                report.push_str(&format!(
+                    "Message: {}\n",
+                    failed_system["message"].as_str().unwrap_or("")
+                ));
+            }
+        }
+        let event = get_system_event(*system_id, action_id);
+        let result_msg = event["result_msg"].as_str().unwrap_or("");
+        match json::parse(result_msg) {
+            Ok(output) => {
+                for failed_state in failed_salt_states(&output) {
+                    error(format!("Failed state {}", failed_state));
+                    report.push_str(&format!("Failed state {}\n", failed_state));
+                }
+            }
+            Err(_) => debug("Event result is not salt state return.".to_string()),
+        }
+        report.push_str(&format!("Event result:\n{}\n", result_msg));
+        save_report(
+            &format!("action-{}-system-{}.log", action_id, system_id),
+            &report,
+        );
+    }
+}
+
+pub fn salt_command_output(output: &JsonValue) -> String {
+    /* Collect stdout and stderr of commands from salt return */
+    let mut text = String::new();
+    for (key, value) in output.entries() {
+        if (key == "stdout" || key == "stderr") && value.is_string() {
+            text.push_str(value.as_str().unwrap());
+            text.push('\n');
+        } else if value.is_object() {
+            text.push_str(&salt_command_output(value));
+        }
+    }
+    text
+}
+
+pub fn kiwi_error_lines(log: &str, limit: usize) -> Vec<String> {
+    /* Last lines of kiwi log at ERROR/FAILED level, or with unresolvable packages */
+    let lines: Vec<String> = log
+        .lines()
+        .filter(|line| {
+            let level = line.trim_start();
+            let lower = line.to_lowercase();
+            level.starts_with("[ ERROR")
+                || level.starts_with("ERROR")
+                || line.contains("FAILED")
+                || lower.contains("nothing provides")
+                || lower.contains("conflict")
+        })
+        .map(|line| line.trim().to_string())
+        .collect();
+    let skip = lines.len().saturating_sub(limit);
+    lines.into_iter().skip(skip).collect()
+}
+
+pub fn report_kiwi_build_failure(kiwi_profile: &str, build_host_id: i32, action_id: i32) {
+    let event = get_system_event(build_host_id, action_id);
+    let result_msg = event["result_msg"].as_str().unwrap_or("");
+    let log = match json::parse(result_msg) {
+        Ok(output) => salt_command_output(&output),
+        Err(_) => result_msg.to_string(),
+    };
+    if log.is_empty() {
+        warning(format!(
+            "No output of kiwi build action {} found at build host.",
+            action_id
+        ));
+        return;
+    }
+    save_report(&format!("kiwi-build-{}.log", kiwi_profile), &log);
+    let mut summary = format!(
+        "Kiwi image building of {} (action {}) failed:\n",
+        kiwi_profile, action_id
+    );
+    for line in kiwi_error_lines(&log, 10) {
+        error(format!("Kiwi: {}", line));
+        summary.push_str(&format!("{}\n", line));
+    }
+    save_report("report.log", &summary);
+}
+
+pub fn create_system_group(group_name: &str, description: &str) -> i32 {
+    let req = Request::new("systemgroup.create")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(group_name)
+        .arg(description)
+        .call_url(read_env("UYUNI_URL"));
+    info(format!("System group {} created.", group_name));
+    let group_id = req.unwrap()["id"].as_i32().unwrap();
+    record_created("system_group", Some(group_id), group_name, None);
+    group_id
+}
+
+pub fn delete_system_group(group_name: &str) -> bool {
+    if !may_delete("system_group", None, group_name, None) {
+        return false;
+    }
+    let req = Request::new("systemgroup.delete")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(group_name)
+        .call_url(read_env("UYUNI_URL"));
+    if req.unwrap().as_i32().unwrap() == 1 {
+        info(format!("System group with name {} deleted.", group_name));
+        forget_created("system_group", None, group_name, None);
+        return true;
+    }
+    false
+}
+
+pub fn exists_system_group(group_name: &str) -> bool {
+    let system_groups = call_server("systemgroup.listAllGroups", Some(read_env("UYUNI_KEY")));
+    for system_group in system_groups.as_array().unwrap() {
+        if system_group["name"].as_str().unwrap() == group_name {
+            warning(format!(
+                "System_group with name {:?} exists.",
+                system_group["name"].as_str().unwrap()
+            ));
+            return true;
+        }
+    }
+    false
+}
+
+pub fn exists_system_group_id(group_id: i32) -> bool {
+    let system_groups = call_server("systemgroup.listAllGroups", Some(read_env("UYUNI_KEY")));
+    system_groups
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|system_group| system_group["id"].as_i32() == Some(group_id))
+}
+
+pub fn update_system_group(group_name: &str, description: &str) -> i32 {
+    let req = Request::new("systemgroup.update")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(group_name)
+        .arg(description)
+        .call_url(read_env("UYUNI_URL"));
+    info(format!(
+        "Description of system group {} updated to {:?}.",
+        group_name, description
+    ));
+    req.unwrap()["id"].as_i32().unwrap()
+}
+
+pub fn ensure_system_group(group_name: &str, description: &str) -> i32 {
+    /* Existing group is kept with its members and formulas unless --recreate is passed
+    and the group was created by the tool (or --force is passed) */
+    let recreate = read_env("UYUNI_RECREATE") == "yes";
+    if exists_system_group(group_name) && !(recreate && delete_system_group(group_name)) {
+        let details = get_system_group_details(group_name);
+        if details["description"].as_str().unwrap_or("") != description {
+            if owns("system_group", None, group_name, None) {
+                update_system_group(group_name, description);
+            } else {
+                warning(format!(
+                    "Description of system group {} not created by the tool is kept.",
+                    group_name
+                ));
+            }
+        }
+        return details["id"].as_i32().unwrap();
+    }
+    create_system_group(group_name, description)
+}
+
+pub fn get_system_group_details(group_name: &str) -> Value {
+    let req = Request::new("systemgroup.getDetails")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(group_name)
+        .call_url(read_env("UYUNI_URL"));
+    req.unwrap()
+}
+
+pub fn add_systems_to_group(group_name: &str, system_ids: Vec<i32>) -> i32 {
+    let req = Request::new("systemgroup.addOrRemoveSystems")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(group_name)
+        .arg(Value::Array(
+            system_ids.iter().map(|id| Value::Int(*id)).collect(),
+        ))
+        .arg(true)
+        .call_url(read_env("UYUNI_URL"));
+    info(format!(
+        "Systems {:?} added to system group {}.",
+        system_ids, group_name
+    ));
+    req.unwrap().as_i32().unwrap()
+}
+
+pub fn get_branch_id() -> String {
+    /* Branch ID group is named by branch_id of pxe part in branch-network formula */
+    let json_data = read_text_file("branch-network.json");
+    let parsed = json::parse(&json_data).unwrap();
+    match parsed["pxe"]["branch_id"].as_str() {
+        Some(branch_id) if !branch_id.is_empty() => branch_id.to_string(),
+        _ => {
+            error("Missing branch_id of pxe in branch-network.json.".to_string());
+            process::exit(1);
+        }
+    }
+}
+
+pub fn get_group_formulas(group_id: i32) -> Vec<String> {
+    let req = Request::new("formula.getFormulasByGroupId")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(group_id)
+        .call_url(read_env("UYUNI_URL"));
+    let mut formulas: Vec<String> = Vec::new();
+    for formula in req.unwrap().as_array().unwrap() {
+        formulas.push(formula.as_str().unwrap().to_string());
+    }
+    formulas
+}
+
+pub fn set_group_formulas(group_id: i32, formulas: Vec<&str>) -> i32 {
+    /* Keep formulas already assigned to group and add only missing ones */
+    let mut formula_names = get_group_formulas(group_id);
+    let mut added: Vec<String> = Vec::new();
+    for formula in formulas {
+        if formula_names.contains(&formula.to_string()) {
+            debug(format!(
+                "Formula *{}* already assigned to group {}.",
+                formula, group_id
+            ));
+        } else {
+            formula_names.push(formula.to_string());
+            added.push(formula.to_string());
+        }
+    }
+    let result = call_group_formulas(group_id, &formula_names);
+    info(format!(
+        "Formulas {:?} enabled for group {}, but not configured yet.",
+        formula_names, group_id
+    ));
+    for formula in added {
+        record_created("group_formula", None, &formula, Some(group_id));
+    }
+    result
+}
+
+fn call_group_formulas(group_id: i32, formula_names: &[String]) -> i32 {
+    let req = Request::new("formula.setFormulasOfGroup")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(group_id)
+        .arg(Value::Array(
+            formula_names
+                .iter()
+                .map(|name| Value::from(name.as_str()))
+                .collect(),
+        ))
+        .call_url(read_env("UYUNI_URL"));
+    req.unwrap().as_i32().unwrap()
+}
+
+pub fn remove_group_formula(group_id: i32, formula_name: &str) -> i32 {
+    if !may_delete("group_formula", None, formula_name, Some(group_id)) {
+        return 0;
+    }
+    let mut formula_names = get_group_formulas(group_id);
+    formula_names.retain(|formula| formula != formula_name);
+    let result = call_group_formulas(group_id, &formula_names);
+    info(format!(
+        "Formula *{}* removed from group {}.",
+        formula_name, group_id
+    ));
+    result
+}
+
+pub fn get_group_formula_data(group_id: i32, formula_name: &str) -> Value {
+    let req = Request::new("formula.getGroupFormulaData")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(group_id)
+        .arg(formula_name)
+        .call_url(read_env("UYUNI_URL"));
+    req.unwrap()
+}
+
+pub fn set_group_formula_data(group_id: i32, formula_name: &str, parsed: &JsonValue) -> i32 {
+    let data = Value::Struct(json_to_btree(parsed));
+    debug(format!("{:?}", data));
+    if get_group_formula_data(group_id, formula_name) == data {
+        info(format!(
+            "*{:?}* formula data of group {} are up to date.",
+            formula_name, group_id
+        ));
+        return 1;
+    }
+    let req = Request::new("formula.setGroupFormulaData")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(group_id)
+        .arg(formula_name)
+        .arg(data)
+        .call_url(read_env("UYUNI_URL"));
+    info(format!(
+        "*{:?}* formula of group {} cofigured.",
+        formula_name, group_id
+    ));
+    req.unwrap().as_i32().unwrap()
+}
+
+pub fn saltboot_partitioning(template: &JsonValue, hwtype: &JsonValue) -> JsonValue {
+    /* Override disk device, partition sizes and image of saltboot template by hwtype */
+    let mut data = template.clone();
+    let disk = &mut data["partitioning"]["disk1"];
+    if hwtype.has_key("device") {
+        disk["device"] = hwtype["device"].clone();
+    }
+    for (partition, size) in hwtype["partitions"].entries() {
+        disk["partitions"][partition]["size_MiB"] = size.clone();
+    }
+    for (_, partition) in disk["partitions"].entries_mut() {
+        if partition["image"].is_empty() {
+            continue;
+        }
+        if hwtype.has_key("image") {
+            partition["image"] = hwtype["image"].clone();
+        }
+        if hwtype.has_key("image_version") {
+            partition["image_version"] = hwtype["image_version"].clone();
+        }
+    }
+    debug(format!(
+        "Saltboot data for {}: {}",
+        hwtype["group"],
+        data.dump()
+    ));
+    data
+}
+
+pub fn configure_group_formulas(group_id: i32, formulas: &JsonValue) {
+    /* Formulas are described as object {"formula": "data_file.json"} */
+    let formula_names: Vec<&str> = formulas.entries().map(|(name, _)| name).collect();
+    set_group_formulas(group_id, formula_names);
+    for (formula_name, data_file) in formulas.entries() {
+        let json_data = read_text_file(data_file.as_str().unwrap());
+        set_group_formula_data(group_id, formula_name, &json::parse(&json_data).unwrap());
+    }
+}
+
+pub fn get_system_formulas(system_id: i32) -> Vec<String> {
+    let req = Request::new("formula.getFormulasByServerId")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(system_id)
+        .call_url(read_env("UYUNI_URL"));
+    value_strings(&req.unwrap())
+}
+
+fn call_system_formulas(system_id: i32, formulas: &[&str]) -> i32 {
+    let req = Request::new("formula.setFormulasOfServer")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(system_id)
+        .arg(Value::Array(
+            formulas.iter().map(|name| Value::from(*name)).collect(),
+        ))
+        .call_url(read_env("UYUNI_URL"));
+    req.unwrap().as_i32().unwrap()
+}
+
+pub fn set_system_formulas(system_id: i32, formulas: Vec<&str>) -> i32 {
+    let existing = get_system_formulas(system_id);
+    let result = call_system_formulas(system_id, &formulas);
+    info("All formulas enabled for system, but not configured yet.".to_string());
+    for formula in formulas {
+        if !existing.contains(&formula.to_string()) {
+            record_created("system_formula", None, formula, Some(system_id));
+        }
+    }
+    result
+}
+
+pub fn remove_system_formula(system_id: i32, formula_name: &str) -> i32 {
+    if !may_delete("system_formula", None, formula_name, Some(system_id)) {
+        return 0;
+    }
+    let existing = get_system_formulas(system_id);
+    let formulas: Vec<&str> = existing
+        .iter()
+        .map(|formula| formula.as_str())
+        .filter(|formula| *formula != formula_name)
+        .collect();
+    let result = call_system_formulas(system_id, &formulas);
+    info(format!(
+        "Formula *{}* removed from system {}.",
+        formula_name, system_id
+    ));
+    result
+}
+
+pub fn set_system_formula_data(system_id: i32, formula_name: &str) -> i32 {
+    let json_data = read_text_file(format!("{}.json", formula_name).as_str());
+    let parsed = &json::parse(&json_data).unwrap();
+    debug(format!("{:?}", json_to_btree(parsed)));
+    let data = Request::new("formula.setSystemFormulaData")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(system_id)
+        .arg(formula_name)
+        .arg(Value::Struct(json_to_btree(parsed)))
+        .call_url(read_env("UYUNI_URL"));
+    info(format!("*{:?}* formula cofigured.", formula_name));
+    data.unwrap().as_i32().unwrap()
+}
+
+pub fn clone_activation_key(key_name: &str) -> String {
     let req = Request::new("activationkey.clone")
         .arg(read_env("UYUNI_KEY"))
         .arg(read_env("UYUNI_CLONNED_KEY"))
         .arg(key_name)
         .call_url(read_env("UYUNI_URL"));
-    info(format!("Activation key with name *{:?}* clonned.", key_name));
-    return req.unwrap().as_str().unwrap().to_string();
+    info(format!(
+        "Activation key with name *{:?}* clonned.",
+        key_name
+    ));
+    let full_key = req.unwrap().as_str().unwrap().to_string();
+    record_created("activation_key", None, &full_key, None);
+    full_key
+}
+
+pub fn get_user_org_id() -> i32 {
+    let req = Request::new("user.getDetails")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(read_env("UYUNI_USER"))
+        .call_url(read_env("UYUNI_URL"));
+    req.unwrap()["org_id"].as_i32().unwrap()
+}
+
+pub fn find_activation_key(key_name: &str) -> Option<String> {
+    let keys = call_server(
+        "activationkey.listActivationKeys",
+        Some(read_env("UYUNI_KEY")),
+    );
+    let full_keys: Vec<String> = keys
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|key| key["key"].as_str().unwrap_or("").to_string())
+        .collect();
+    let matching = matching_keys(&full_keys, key_name, get_user_org_id());
+    if matching.len() > 1 {
+        error(format!(
+            "Activation key {} is ambiguous, it matches keys {:?}.",
+            key_name, matching
+        ));
+        process::exit(1);
+    }
+    matching.into_iter().next()
+}
+
+fn matching_keys(full_keys: &[String], key_name: &str, org_id: i32) -> Vec<String> {
+    /* Keys are listed with organization prefix, e.g. 1-sle15sp2_retail_key */
+    let prefixed = format!("{}-{}", org_id, key_name);
+    full_keys
+        .iter()
+        .filter(|full_key| **full_key == key_name || **full_key == prefixed)
+        .cloned()
+        .collect()
+}
+
+pub fn create_activation_key(spec: &JsonValue) -> String {
+    let req = Request::new("activationkey.create")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(spec["key"].to_string())
+        .arg(spec["description"].as_str().unwrap_or("").to_string())
+        .arg(spec["base_channel"].as_str().unwrap_or("").to_string())
+        .arg(Value::Array(json_array_to_xmlrpc(
+            spec["entitlements"].clone(),
+        )))
+        .arg(spec["universal_default"].as_bool().unwrap_or(false))
+        .call_url(read_env("UYUNI_URL"));
+    let full_key = req.unwrap().as_str().unwrap().to_string();
+    info(format!("Activation key {} created.", full_key));
+    record_created("activation_key", None, &full_key, None);
+    full_key
+}
+
+pub fn delete_activation_key(full_key: &str) -> i32 {
+    if !may_delete("activation_key", None, full_key, None) {
+        return 0;
+    }
+    let req = Request::new("activationkey.delete")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(full_key)
+        .call_url(read_env("UYUNI_URL"));
+    let result = req.unwrap().as_i32().unwrap();
+    if result == 1 {
+        info(format!("Activation key {} deleted.", full_key));
+        forget_created("activation_key", None, full_key, None);
+    }
+    result
+}
+
+fn call_activation_key(xmlrpc_method: &str, full_key: &str, values: Vec<Value>) -> i32 {
+    let req = Request::new(xmlrpc_method)
+        .arg(read_env("UYUNI_KEY"))
+        .arg(full_key)
+        .arg(Value::Array(values))
+        .call_url(read_env("UYUNI_URL"));
+    req.unwrap().as_i32().unwrap()
+}
+
+fn missing_values(expected: &JsonValue, present: &[String]) -> Vec<String> {
+    expected
+        .members()
+        .map(|value| value.to_string())
+        .filter(|value| !present.contains(value))
+        .collect()
+}
+
+fn extra_values(expected: &JsonValue, present: &[String]) -> Vec<String> {
+    /* Values beyond spec are drift only when spec lists the values at all */
+    if !expected.is_array() {
+        return Vec::new();
+    }
+    present
+        .iter()
+        .filter(|value| !expected.contains(value.as_str()))
+        .cloned()
+        .collect()
+}
+
+fn value_strings(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .unwrap_or(&[])
+        .iter()
+        .map(|item| item.as_str().unwrap_or("").to_string())
+        .collect()
+}
+
+pub fn reconcile_activation_key(full_key: &str, spec: &JsonValue, fix: bool) -> Vec<String> {
+    /* Returns drift of key against spec, with fix the drift is corrected at server */
+    let mut drift: Vec<String> = Vec::new();
+    let details = get_activation_key_details(full_key);
+    let mut changed: BTreeMap<String, Value> = BTreeMap::new();
+    for (spec_name, detail_name) in [
+        ("description", "description"),
+        ("base_channel", "base_channel_label"),
+        ("contact_method", "contact_method"),
+    ] {
+        if let Some(expected) = spec[spec_name].as_str() {
+            if details[detail_name].as_str() != Some(expected) {
+                drift.push(format!(
+                    "{} is {:?}, expected {:?}.",
+                    detail_name, details[detail_name], expected
+                ));
+                changed.insert(detail_name.to_string(), Value::from(expected));
+            }
+        }
+    }
+    if let Some(expected) = spec["universal_default"].as_bool() {
+        if details["universal_default"].as_bool() != Some(expected) {
+            drift.push(format!("universal_default is not {}.", expected));
+            changed.insert("universal_default".to_string(), Value::Bool(expected));
+        }
+    }
+    if fix && !changed.is_empty() {
+        Request::new("activationkey.setDetails")
+            .arg(read_env("UYUNI_KEY"))
+            .arg(full_key)
+            .arg(Value::Struct(changed))
+            .call_url(read_env("UYUNI_URL"))
+            .unwrap();
+    }
+
+    let present = value_strings(&details["child_channel_labels"]);
+    let children = missing_values(&spec["child_channels"], &present);
+    if !children.is_empty() {
+        drift.push(format!("Child channels {:?} are missing.", children));
+        if fix {
+            let values = children.iter().map(|c| Value::from(c.as_str())).collect();
+            call_activation_key("activationkey.addChildChannels", full_key, values);
+        }
+    }
+    let children = extra_values(&spec["child_channels"], &present);
+    if !children.is_empty() {
+        drift.push(format!("Child channels {:?} are extra.", children));
+        if fix {
+            let values = children.iter().map(|c| Value::from(c.as_str())).collect();
+            call_activation_key("activationkey.removeChildChannels", full_key, values);
+        }
+    }
+
+    let present = value_strings(&details["entitlements"]);
+    let entitlements = missing_values(&spec["entitlements"], &present);
+    if !entitlements.is_empty() {
+        drift.push(format!("Entitlements {:?} are missing.", entitlements));
+        if fix {
+            let values = entitlements
+                .iter()
+                .map(|e| Value::from(e.as_str()))
+                .collect();
+            call_activation_key("activationkey.addEntitlements", full_key, values);
+        }
+    }
+    let entitlements = extra_values(&spec["entitlements"], &present);
+    if !entitlements.is_empty() {
+        drift.push(format!("Entitlements {:?} are extra.", entitlements));
+        if fix {
+            let values = entitlements
+                .iter()
+                .map(|e| Value::from(e.as_str()))
+                .collect();
+            call_activation_key("activationkey.removeEntitlements", full_key, values);
+        }
+    }
+
+    let group_ids: Vec<i32> = details["server_group_ids"]
+        .as_array()
+        .unwrap_or(&[])
+        .iter()
+        .filter_map(|id| id.as_i32())
+        .collect();
+    let mut groups: Vec<i32> = Vec::new();
+    let mut expected_ids: Vec<i32> = Vec::new();
+    for group in spec["server_groups"].members() {
+        if !exists_system_group(group.as_str().unwrap()) {
+            drift.push(format!("Server group {} does not exist.", group));
+            continue;
+        }
+        let group_id = get_system_group_details(group.as_str().unwrap())["id"]
+            .as_i32()
+            .unwrap();
+        expected_ids.push(group_id);
+        if !group_ids.contains(&group_id) {
+            drift.push(format!("Server group {} is missing.", group));
+            groups.push(group_id);
+        }
+    }
+    if fix && !groups.is_empty() {
+        let values = groups.iter().map(|id| Value::Int(*id)).collect();
+        call_activation_key("activationkey.addServerGroups", full_key, values);
+    }
+    if spec["server_groups"].is_array() {
+        let extra: Vec<i32> = group_ids
+            .into_iter()
+            .filter(|id| !expected_ids.contains(id))
+            .collect();
+        if !extra.is_empty() {
+            drift.push(format!("Server groups with ids {:?} are extra.", extra));
+            if fix {
+                let values = extra.iter().map(|id| Value::Int(*id)).collect();
+                call_activation_key("activationkey.removeServerGroups", full_key, values);
+            }
+        }
+    }
+
+    if !spec["config_channels"].is_empty() {
+        let config_channels = Request::new("activationkey.listConfigChannels")
+            .arg(read_env("UYUNI_KEY"))
+            .arg(full_key)
+            .call_url(read_env("UYUNI_URL"))
+            .unwrap();
+        let labels: Vec<String> = config_channels
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|channel| channel["label"].as_str().unwrap_or("").to_string())
+            .collect();
+        let missing = missing_values(&spec["config_channels"], &labels);
+        if !missing.is_empty() {
+            drift.push(format!("Config channels {:?} are missing.", missing));
+            if fix {
+                Request::new("activationkey.addConfigChannels")
+                    .arg(read_env("UYUNI_KEY"))
+                    .arg(Value::Array(vec![Value::from(full_key)]))
+                    .arg(Value::Array(
+                        missing.iter().map(|c| Value::from(c.as_str())).collect(),
+                    ))
+                    .arg(false)
+                    .call_url(read_env("UYUNI_URL"))
+                    .unwrap();
+            }
+        }
+        let extra = extra_values(&spec["config_channels"], &labels);
+        if !extra.is_empty() {
+            drift.push(format!("Config channels {:?} are extra.", extra));
+            if fix {
+                Request::new("activationkey.removeConfigChannels")
+                    .arg(read_env("UYUNI_KEY"))
+                    .arg(Value::Array(vec![Value::from(full_key)]))
+                    .arg(Value::Array(
+                        extra.iter().map(|c| Value::from(c.as_str())).collect(),
+                    ))
+                    .call_url(read_env("UYUNI_URL"))
+                    .unwrap();
+            }
+        }
+    }
+
+    let packages: Vec<String> = details["packages"]
+        .as_array()
+        .unwrap_or(&[])
+        .iter()
+        .map(|package| package["name"].as_str().unwrap_or("").to_string())
+        .collect();
+    let missing = missing_values(&spec["packages"], &packages);
+    if !missing.is_empty() {
+        drift.push(format!("Packages {:?} are missing.", missing));
+        if fix {
+            let values = missing
+                .iter()
+                .map(|name| {
+                    let mut package: BTreeMap<String, Value> = BTreeMap::new();
+                    package.insert("name".to_string(), Value::from(name.as_str()));
+                    Value::Struct(package)
+                })
+                .collect();
+            call_activation_key("activationkey.addPackages", full_key, values);
+        }
+    }
+    let extra = extra_values(&spec["packages"], &packages);
+    if !extra.is_empty() {
+        drift.push(format!("Packages {:?} are extra.", extra));
+        if fix {
+            let values = extra
+                .iter()
+                .map(|name| {
+                    let mut package: BTreeMap<String, Value> = BTreeMap::new();
+                    package.insert("name".to_string(), Value::from(name.as_str()));
+                    Value::Struct(package)
+                })
+                .collect();
+            call_activation_key("activationkey.removePackages", full_key, values);
+        }
+    }
+    for item in &drift {
+        warning(format!("Activation key {}: {}", full_key, item));
+    }
+    if fix && !drift.is_empty() {
+        info(format!("Activation key {} reconciled.", full_key));
+    }
+    drift
+}
+
+pub fn find_system_id(system_name: &str) -> Option<i32> {
+    let req = Request::new("system.getId")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(system_name)
+        .call_url(read_env("UYUNI_URL"));
+    req.unwrap()[0]["id"].as_i32()
+}
+
+pub fn list_pending_keys() -> Vec<String> {
+    let keys = call_server("saltkey.pendingList", Some(read_env("UYUNI_KEY")));
+    value_strings(&keys)
+}
+
+pub fn accept_salt_key(minion_id: &str) -> i32 {
+    let req = Request::new("saltkey.accept")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(minion_id)
+        .call_url(read_env("UYUNI_URL"));
+    info(format!("Salt key of {} accepted.", minion_id));
+    req.unwrap().as_i32().unwrap()
+}
+
+pub fn list_system_groups(system_id: i32) -> Vec<String> {
+    /* Names of groups the system is member of */
+    let groups = Request::new("system.listGroups")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(system_id)
+        .call_url(read_env("UYUNI_URL"));
+    let mut names: Vec<String> = Vec::new();
+    for group in groups.unwrap().as_array().unwrap() {
+        if group["subscribed"].as_i32() == Some(1) {
+            names.push(
+                group["system_group_name"]
+                    .as_str()
+                    .unwrap_or("")
+                    .to_string(),
+            );
+        }
+    }
+    names
+}
+
+pub fn status_saltboot(system_id: i32) -> Option<bool> {
+    /* Result of newest saltboot state apply at system, None while it is not finished */
+    let events = Request::new("system.listSystemEvents")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(system_id)
+        .call_url(read_env("UYUNI_URL"));
+    let mut newest: Option<(chrono::NaiveDateTime, Option<bool>)> = None;
+    for event in events.unwrap().as_array().unwrap() {
+        let action_type = event["action_type"].as_str().unwrap_or("").to_lowercase();
+        let name = event["name"].as_str().unwrap_or("").to_lowercase();
+        let summary = event["summary"].as_str().unwrap_or("").to_lowercase();
+        if !action_type.contains("state")
+            || !(name.contains("saltboot") || summary.contains("saltboot"))
+        {
+            continue;
+        }
+        let time = match value_to_datetime(&event["completed"])
+            .or_else(|| value_to_datetime(&event["created"]))
+        {
+            Some(time) => time,
+            None => continue,
+        };
+        let status = if event["failed_count"].as_i32().unwrap_or(0) > 0 {
+            Some(false)
+        } else if event["successful_count"].as_i32().unwrap_or(0) > 0 {
+            Some(true)
+        } else {
+            None
+        };
+        if newest.is_none_or(|(newest_time, _)| time > newest_time) {
+            newest = Some((time, status));
+        }
+    }
+    newest.and_then(|(_, status)| status)
+}
+
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    /* Pattern with * matching any sequence of characters */
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == text;
+    }
+    let mut rest = match text.strip_prefix(parts[0]) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(parts[parts.len() - 1])
+}
+
+pub fn list_systems() -> Vec<Value> {
+    let systems = call_server("system.listSystems", Some(read_env("UYUNI_KEY")));
+    systems.as_array().unwrap().to_vec()
+}
+
+pub fn list_system_macs(system_id: i32) -> Vec<String> {
+    let devices = Request::new("system.getNetworkDevices")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(system_id)
+        .call_url(read_env("UYUNI_URL"));
+    devices
+        .unwrap()
+        .as_array()
+        .unwrap_or(&[])
+        .iter()
+        .map(|device| {
+            device["hardware_address"]
+                .as_str()
+                .unwrap_or("")
+                .to_lowercase()
+        })
+        .collect()
+}
+
+pub fn schedule_script_run(system_ids: &[i32], script: &str, timeout: i32) -> i32 {
+    let now = iso8601::datetime(&chrono::offset::Utc::now().to_rfc3339()).unwrap();
+    let req = Request::new("system.scheduleScriptRun")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(Value::Array(
+            system_ids.iter().map(|id| Value::from(*id)).collect(),
+        ))
+        .arg("root")
+        .arg("root")
+        .arg(timeout)
+        .arg(script)
+        .arg(Value::from(now))
+        .call_url(read_env("UYUNI_URL"));
+    req.unwrap().as_i32().unwrap()
+}
+
+pub fn terminal_image_versions(system_ids: &[i32]) -> HashMap<i32, String> {
+    /* Version of image booted by saltboot as reported by saltboot_image grain of terminal */
+    let mut versions = HashMap::new();
+    if system_ids.is_empty() {
+        return versions;
+    }
+    let script = "#!/bin/sh\nsalt-call --local --out=newline_values_only grains.get saltboot_image:version\n";
+    let action_id = schedule_script_run(system_ids, script, 60);
+    wait_for_action(
+        action_id,
+        system_ids,
+        read_env_or("UYUNI_SCRIPT_INTERVAL", 10),
+        read_env_or("UYUNI_SCRIPT_TIMEOUT", 300),
+    );
+    let results = Request::new("system.getScriptResults")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(action_id)
+        .call_url(read_env("UYUNI_URL"));
+    for result in results.unwrap().as_array().unwrap_or(&[]) {
+        let version = result["output"].as_str().unwrap_or("").trim();
+        if result["returnCode"].as_i32() == Some(0) && !version.is_empty() {
+            versions.insert(result["serverId"].as_i32().unwrap(), version.to_string());
+        }
+    }
+    versions
+}
+
+fn bootstrap_password(hostname: &str) -> String {
+    /* Password is never stored in bootstrap.json, it is taken from environment or asked for */
+    if let Ok(password) = env::var("UYUNI_BOOTSTRAP_PASSWORD") {
+        return password;
+    }
+    if read_env("UYUNI_YES") == "yes" {
+        error(format!(
+            "No password to bootstrap {}, set UYUNI_BOOTSTRAP_PASSWORD or private_key_file.",
+            hostname
+        ));
+        process::exit(1);
+    }
+    info(format!("Password to bootstrap {}:", hostname));
+    let password = input().trim_end_matches(&['\r', '\n'][..]).to_string();
+    if password.is_empty() {
+        error(format!("No password to bootstrap {} given.", hostname));
+        process::exit(1);
+    }
+    password
+}
+
+pub fn bootstrap_system(host: &JsonValue) -> i32 {
+    /* Host is bootstrapped with password or with private key read from file */
+    let hostname = host["host"].to_string();
+    let mut req = Request::new(if host["private_key_file"].is_string() {
+        "system.bootstrapWithPrivateSshKey"
+    } else {
+        "system.bootstrap"
+    })
+    .arg(read_env("UYUNI_KEY"))
+    .arg(hostname.clone())
+    .arg(host["ssh_port"].as_i32().unwrap_or(22))
+    .arg(host["user"].as_str().unwrap_or("root").to_string());
+    if let Some(key_file) = host["private_key_file"].as_str() {
+        let private_key = match fs::read_to_string(key_file) {
+            Ok(private_key) => private_key,
+            Err(reason) => panic!("Cannot read private key, because {:?}", reason),
+        };
+        req = req.arg(private_key).arg(
+            host["private_key_password"]
+                .as_str()
+                .unwrap_or("")
+                .to_string(),
+        );
+    } else {
+        req = req.arg(bootstrap_password(&hostname));
+    }
+    let result = req
+        .arg(host["activation_key"].to_string())
+        .arg(host["salt_ssh"].as_bool().unwrap_or(false))
+        .call_url(read_env("UYUNI_URL"));
+    info(format!("Bootstrap of {} finished.", hostname));
+    result.unwrap().as_i32().unwrap()
+}
+
+pub fn wait_for_registration(system_name: &str, step_time: u64, timeout: u64) -> Option<i32> {
+    let step = time::Duration::from_secs(step_time);
+    let mut elapsed = 0;
+    loop {
+        if let Some(system_id) = find_system_id(system_name) {
+            info(format!(
+                "System {} registered with id {} after {} seconds.",
+                system_name, system_id, elapsed
+            ));
+            return Some(system_id);
+        }
+        if elapsed >= timeout {
+            return None;
+        }
+        info(format!(
+            "System {} is not registered yet after {} seconds.",
+            system_name, elapsed
+        ));
+        thread::sleep(step);
+        elapsed += step_time;
+    }
+}
+
+pub fn is_proxy(system_id: i32) -> bool {
+    let proxies = call_server("proxy.listProxies", Some(read_env("UYUNI_KEY")));
+    proxies
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|proxy| proxy["id"].as_i32() == Some(system_id))
+}
+
+pub fn check_branch_server(branch_server: &str, settings: &JsonValue) -> Vec<String> {
+    let mut failures: Vec<String> = Vec::new();
+    let system_id = get_system_id(branch_server.to_string());
+    if !is_proxy(system_id) {
+        failures.push(format!("Branch server {} is not a proxy.", branch_server));
+    }
+    let packages = Request::new("system.listInstalledPackages")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(system_id)
+        .call_url(read_env("UYUNI_URL"))
+        .unwrap();
+    let installed: Vec<&str> = packages
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|package| package["name"].as_str().unwrap_or(""))
+        .collect();
+    for package in settings["required_packages"].members() {
+        if !installed.contains(&package.as_str().unwrap_or("")) {
+            failures.push(format!(
+                "Package {} is not installed at branch server {}.",
+                package, branch_server
+            ));
+        }
+    }
+    let required: Vec<String> = settings["required_channels"]
+        .members()
+        .map(|channel| channel.to_string())
+        .collect();
+    for channel in missing_channels(&list_system_channels(system_id), &required) {
+        failures.push(format!(
+            "Branch server {} is not subscribed to {} channel.",
+            branch_server, channel
+        ));
+    }
+    let branch_network = json::parse(&read_text_file("branch-network.json")).unwrap();
+    let nic = branch_network["branch_network"]["nic"].to_string();
+    let devices = Request::new("system.getNetworkDevices")
+        .arg(read_env("UYUNI_KEY"))
+        .arg(system_id)
+        .call_url(read_env("UYUNI_URL"))
+        .unwrap();
+    if !devices
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|device| device["interface"].as_str() == Some(&nic))
+    {
+        failures.push(format!(
+            "Network device {} of branch_network does not exist at branch server {}.",
+            nic, branch_server
+        ));
+    }
+    failures
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcard_match_trailing_star() {
+        assert!(wildcard_match(
+            "terminal*",
+            "terminal-sles15sp2.example.org"
+        ));
+        assert!(wildcard_match("terminal*", "terminal"));
+        assert!(!wildcard_match("terminal*", "my-terminal"));
+    }
+
+    #[test]
+    fn wildcard_match_leading_star() {
+        assert!(wildcard_match("*.example.org", "terminal.example.org"));
+        assert!(!wildcard_match("*.example.org", "terminal.example.com"));
+    }
+
+    #[test]
+    fn wildcard_match_middle_star() {
+        assert!(wildcard_match(
+            "terminal-*.example.org",
+            "terminal-15.example.org"
+        ));
+        assert!(!wildcard_match(
+            "terminal-*.example.org",
+            "terminal-15.example.com"
+        ));
+        assert!(!wildcard_match(
+            "terminal-*.example.org",
+            "server-15.example.org"
+        ));
+    }
+
+    #[test]
+    fn wildcard_match_without_star() {
+        assert!(wildcard_match("terminal", "terminal"));
+        assert!(!wildcard_match("terminal", "terminal-1"));
+    }
+
+    #[test]
+    fn wildcard_match_overlapping_parts() {
+        assert!(!wildcard_match("ab*ba", "aba"));
+        assert!(wildcard_match("ab*ba", "abba"));
+        assert!(wildcard_match("a*a*a", "aaa"));
+        assert!(!wildcard_match("a*a*a", "aa"));
+    }
+
+    #[test]
+    fn saltboot_partitioning_overrides_device_sizes_and_image() {
+        env::set_var("UYUNI_LOG_LEVEL", "NO");
+        let template = json::parse(include_str!("../assets/15sp2/saltboot.json")).unwrap();
+        let hwtype = json::object! {
+            "group" => "HWTYPE:Intel-Genuine15",
+            "device" => "/dev/vda",
+            "partitions" => json::object! {"p1" => 256, "p3" => 8192},
+            "image" => "POS_Image_JeOS8",
+            "image_version" => "8.0.0"
+        };
+        let data = saltboot_partitioning(&template, &hwtype);
+        let disk = &data["partitioning"]["disk1"];
+        assert_eq!(disk["device"], "/dev/vda");
+        assert_eq!(disk["partitions"]["p1"]["size_MiB"], 256);
+        assert_eq!(disk["partitions"]["p2"]["size_MiB"], 1024);
+        assert_eq!(disk["partitions"]["p3"]["size_MiB"], 8192);
+        assert_eq!(disk["partitions"]["p3"]["image"], "POS_Image_JeOS8");
+        assert_eq!(disk["partitions"]["p3"]["image_version"], "8.0.0");
+        assert_eq!(disk["partitions"]["p1"]["image"], "");
+        assert_eq!(disk["partitions"]["p2"]["image_version"], "");
+    }
+
+    #[test]
+    fn saltboot_partitioning_keeps_template_without_overrides() {
+        env::set_var("UYUNI_LOG_LEVEL", "NO");
+        let template = json::parse(include_str!("../assets/15sp2/saltboot.json")).unwrap();
+        let hwtype = json::object! {"group" => "HWTYPE:Intel-Genuine11"};
+        assert_eq!(saltboot_partitioning(&template, &hwtype), template);
+    }
+
+    const HIGHSTATE_RETURN: &str = r#"{
+        "pkg_|-mgr_install_docker_|-docker_|-installed": {
+            "__id__": "mgr_install_docker",
+            "__run_num__": 3,
+            "changes": {},
+            "comment": "The following packages failed to install/update: docker",
+            "duration": 2810.5,
+            "name": "docker",
+            "result": false,
+            "start_time": "10:12:01.120000"
+        },
+        "service_|-mgr_docker_service_|-docker_|-running": {
+            "__id__": "mgr_docker_service",
+            "__run_num__": 4,
+            "changes": {},
+            "comment": "One or more requisite failed: mgr_install_docker",
+            "name": "docker",
+            "result": false
+        },
+        "file_|-/etc/sysconfig/proxy_|-/etc/sysconfig/proxy_|-managed": {
+            "__id__": "/etc/sysconfig/proxy",
+            "changes": {},
+            "comment": "File /etc/sysconfig/proxy is in the correct state",
+            "name": "/etc/sysconfig/proxy",
+            "result": true
+        }
+    }"#;
+
+    #[test]
+    fn failed_salt_states_of_highstate() {
+        let failed = failed_salt_states(&json::parse(HIGHSTATE_RETURN).unwrap());
+        assert_eq!(
+            failed,
+            vec![
+                "pkg_|-mgr_install_docker_|-docker_|-installed: The following packages failed to install/update: docker",
+                "service_|-mgr_docker_service_|-docker_|-running: One or more requisite failed: mgr_install_docker",
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_salt_states_of_nested_return() {
+        let output = json::parse(&format!(r#"{{"suma-build": {}}}"#, HIGHSTATE_RETURN)).unwrap();
+        assert_eq!(failed_salt_states(&output).len(), 2);
+        let succeeded = json::parse(
+            r#"{"cmd_|-true_|-true_|-run": {"changes": {"retcode": 0}, "comment": "Command \"true\" run", "result": true}}"#,
+        )
+        .unwrap();
+        assert!(failed_salt_states(&succeeded).is_empty());
+    }
+
+    const KIWI_BUILD_RETURN: &str = r#"{
+        "cmd_|-mgr_buildimage_kiwi_|-kiwi-ng --profile Default system build_|-run": {
+            "__id__": "mgr_buildimage_kiwi",
+            "changes": {
+                "pid": 4242,
+                "retcode": 1,
+                "stderr": "Failed with exit code 1",
+                "stdout": "[ INFO    ]: 10:21:02 | Setting up repository https://download.example.org/SLE-Module-Basesystem\n[ INFO    ]: 10:21:03 | --> Type: rpm-md\n[ INFO    ]: 10:21:05 | Package kernel-firmware-all not found, using default\n[ INFO    ]: 10:21:30 | Installing system (chroot) for build type: pxe\n[ ERROR   ]: 10:21:40 | KiwiInstallPhaseFailed: System package installation failed: Problem: nothing provides libfoo.so.1 needed by POS_Image-base-1.0\n[ INFO    ]: 10:21:41 | Cleaning up BootstrapPhase instance"
+            },
+            "comment": "Command \"kiwi-ng --profile Default system build\" run",
+            "result": false
+        }
+    }"#;
+
+    #[test]
+    fn salt_command_output_of_kiwi_build() {
+        let text = salt_command_output(&json::parse(KIWI_BUILD_RETURN).unwrap());
+        assert!(text.contains("Setting up repository"));
+        assert!(text.contains("KiwiInstallPhaseFailed"));
+        assert!(text.starts_with("Failed with exit code 1\n"));
+        assert_eq!(text.lines().count(), 7);
+    }
+
+    #[test]
+    fn kiwi_error_lines_skip_info_noise() {
+        let text = salt_command_output(&json::parse(KIWI_BUILD_RETURN).unwrap());
+        let lines = kiwi_error_lines(&text, 20);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with("[ ERROR   ]"));
+        assert!(lines[0].contains("nothing provides libfoo.so.1"));
+    }
+
+    #[test]
+    fn kiwi_error_lines_keep_last_lines() {
+        let log =
+            "[ ERROR   ]: first\nfile conflicts with package a\n[ INFO    ]: done\nERROR: last";
+        assert_eq!(
+            kiwi_error_lines(log, 2),
+            vec!["file conflicts with package a", "ERROR: last"]
+        );
+    }
+
+    fn image_value(id: i32, profile: Option<&str>, name: &str, revision: i32) -> Value {
+        let mut image: BTreeMap<String, Value> = BTreeMap::new();
+        image.insert("id".to_string(), Value::from(id));
+        if let Some(profile) = profile {
+            image.insert("profileLabel".to_string(), Value::from(profile));
+        }
+        image.insert("name".to_string(), Value::from(name));
+        image.insert("version".to_string(), Value::from("7.0.0"));
+        image.insert("revision".to_string(), Value::from(revision));
+        image.insert("buildStatus".to_string(), Value::from("completed"));
+        Value::Struct(image)
+    }
+
+    #[test]
+    fn matching_images_of_profiles_sharing_image_name() {
+        env::set_var("UYUNI_LOG_LEVEL", "NO");
+        let images = Value::Array(vec![
+            image_value(11, Some("jeos7"), "POS_Image_JeOS7", 2),
+            image_value(12, Some("jeos7-test"), "POS_Image_JeOS7", 1),
+            image_value(13, Some("jeos7"), "POS_Image_JeOS7", 1),
+            image_value(14, None, "POS_Image_JeOS7", 0),
+        ]);
+        let ids: Vec<i32> = matching_images(&images, "jeos7", "POS_Image_JeOS7", "7.0.0")
+            .iter()
+            .map(|image| image.id)
+            .collect();
+        assert_eq!(ids, vec![14, 13, 11]);
+        let ids: Vec<i32> = matching_images(&images, "jeos7-test", "POS_Image_JeOS7", "7.0.0")
+            .iter()
+            .map(|image| image.id)
+            .collect();
+        assert_eq!(ids, vec![14, 12]);
+    }
+
+    #[test]
+    fn matching_keys_of_organization() {
+        let keys: Vec<String> = ["1-retail", "1-old-retail", "11-retail", "retail"]
+            .iter()
+            .map(|key| key.to_string())
+            .collect();
+        assert_eq!(
+            matching_keys(&keys, "retail", 1),
+            vec!["1-retail", "retail"]
+        );
+        assert_eq!(matching_keys(&keys, "old-retail", 1), vec!["1-old-retail"]);
+        assert_eq!(
+            matching_keys(&keys, "retail", 11),
+            vec!["11-retail", "retail"]
+        );
+        assert_eq!(matching_keys(&keys, "retail", 2), vec!["retail"]);
+    }
+
+    #[test]
+    fn extra_values_only_for_listed_spec() {
+        let present = vec!["a".to_string(), "b".to_string()];
+        assert_eq!(extra_values(&json::array!["a"], &present), vec!["b"]);
+        assert!(extra_values(&json::array!["a", "b", "c"], &present).is_empty());
+        assert!(extra_values(&JsonValue::Null, &present).is_empty());
+    }
 }